@@ -0,0 +1,168 @@
+use crate::services::extraction::ExtractionError;
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+
+#[derive(serde::Deserialize)]
+pub struct StartExtractionRequest {
+    pub countries: Vec<String>,
+}
+
+pub async fn list_extractions(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    let jobs = app_state.extraction_service.get_jobs().await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": jobs
+    }))
+}
+
+pub async fn get_extraction(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Json<serde_json::Value> {
+    match app_state.extraction_service.get_job(&country_code).await {
+        Some(job) => Json(serde_json::json!({
+            "success": true,
+            "data": job
+        })),
+        None => Json(serde_json::json!({
+            "success": false,
+            "error": format!("No extraction job found for country: {}", country_code)
+        })),
+    }
+}
+
+pub async fn start_extraction(
+    State(app_state): State<AppState>,
+    Json(request): Json<StartExtractionRequest>,
+) -> Json<serde_json::Value> {
+    if request.countries.is_empty() {
+        return Json(serde_json::json!({
+            "success": false,
+            "error": "At least one country code is required"
+        }));
+    }
+
+    let countries = app_state
+        .country_service
+        .get_countries_to_process(&request.countries);
+
+    if countries.is_empty() {
+        return Json(serde_json::json!({
+            "success": false,
+            "error": "No known country codes provided"
+        }));
+    }
+
+    app_state
+        .extraction_service
+        .start_extraction(countries.clone());
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "countries": countries
+        }
+    }))
+}
+
+pub async fn refresh_extraction(
+    State(app_state): State<AppState>,
+    Json(request): Json<StartExtractionRequest>,
+) -> Json<serde_json::Value> {
+    let countries = app_state
+        .country_service
+        .get_countries_to_process(&request.countries);
+
+    if countries.is_empty() {
+        return Json(serde_json::json!({
+            "success": false,
+            "error": "No known country codes provided"
+        }));
+    }
+
+    app_state
+        .extraction_service
+        .start_refresh(countries.clone());
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "countries": countries
+        }
+    }))
+}
+
+pub async fn pause_extraction(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Json<serde_json::Value> {
+    job_response(app_state.extraction_service.pause_job(&country_code).await)
+}
+
+pub async fn resume_extraction(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Json<serde_json::Value> {
+    job_response(app_state.extraction_service.resume_job(&country_code).await)
+}
+
+pub async fn cancel_extraction(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Json<serde_json::Value> {
+    job_response(app_state.extraction_service.cancel_job(&country_code).await)
+}
+
+pub async fn retry_extraction(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Json<serde_json::Value> {
+    match app_state
+        .extraction_service
+        .retry_failed_localities(&country_code)
+        .await
+    {
+        Ok(retried) => Json(serde_json::json!({
+            "success": true,
+            "data": {
+                "retried": retried
+            }
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "error": e.to_string()
+        })),
+    }
+}
+
+pub async fn get_storage_report(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    match app_state.storage_service.report().await {
+        Ok(report) => Json(serde_json::json!({
+            "success": true,
+            "data": report
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "error": format!("Failed to compute storage report: {}", e)
+        })),
+    }
+}
+
+fn job_response<T: serde::Serialize>(
+    result: Result<T, ExtractionError>,
+) -> Json<serde_json::Value> {
+    match result {
+        Ok(job) => Json(serde_json::json!({
+            "success": true,
+            "data": job
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "error": e.to_string()
+        })),
+    }
+}
@@ -1,6 +1,9 @@
 use crate::AppState;
 use axum::{
-    extract::{Query, State},
+    body::Body,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Response,
     Json,
 };
 
@@ -74,3 +77,55 @@ pub async fn search_countries(
         }
     }))
 }
+
+/// Serves the cached manifest of a country, generating it on first request.
+pub async fn get_country_manifest(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+) -> Result<Response<Body>, StatusCode> {
+    if app_state
+        .country_service
+        .get_country_name(&country_code)
+        .is_none()
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let manifest_path = app_state.extraction_service.manifest_path(&country_code);
+
+    let content = match tokio::fs::read(&manifest_path).await {
+        Ok(content) => content,
+        Err(_) => {
+            // Countries outside TARGET_COUNTRIES have a manifest once an archive was extracted on demand
+            let config = app_state.config.lock().await;
+            let has_archives = app_state
+                .country_service
+                .get_countries_to_process(&config.target_countries)
+                .contains(&country_code)
+                || config.localities_dir().join(&country_code).is_dir();
+            drop(config);
+
+            if !has_archives {
+                return Err(StatusCode::NOT_FOUND);
+            }
+
+            let manifest = app_state
+                .extraction_service
+                .update_manifest(&country_code)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            serde_json::to_vec(&manifest).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+    };
+
+    let signature = app_state.signing_service.sign(&content);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", content.len().to_string())
+        .header("X-Signature", signature)
+        .body(Body::from(content))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
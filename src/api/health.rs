@@ -0,0 +1,127 @@
+use crate::models::tor::{OnionState, TorStatus};
+use crate::AppState;
+use axum::{extract::State, http::header, response::IntoResponse, Json};
+use std::fmt::Write as _;
+
+pub async fn health_check(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    let planet_build = app_state
+        .extraction_service
+        .get_active_planet()
+        .await
+        .map(|planet| planet.build);
+
+    let (serve_http, onion_address) = {
+        let config = app_state.config.lock().await;
+        (config.serve_http, config.onion_address.clone())
+    };
+    let tor_status = app_state.tor_status.snapshot();
+
+    // An enabled onion service that cannot be reached degrades the instance
+    let status = if tor_status.state == OnionState::Disabled || tor_status.is_reachable() {
+        "healthy"
+    } else {
+        "degraded"
+    };
+
+    Json(serde_json::json!({
+        "status": status,
+        "planet_build": planet_build,
+        "http": {
+            "enabled": serve_http
+        },
+        "tor": {
+            "onion_address": onion_address,
+            "status": tor_status
+        }
+    }))
+}
+
+/// Prometheus metrics of the onion service.
+pub async fn get_metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    let tor_status = app_state.tor_status.snapshot();
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&tor_status),
+    )
+}
+
+fn render_metrics(status: &TorStatus) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "localitysrv_onion_up",
+        "gauge",
+        "Whether the onion service is reachable, possibly degraded.",
+        &[(String::new(), status.is_reachable() as u8 as f64)],
+    );
+    metric(
+        "localitysrv_onion_state",
+        "gauge",
+        "The current state of the onion service.",
+        &OnionState::ALL
+            .iter()
+            .map(|state| {
+                (
+                    format!("{{state=\"{}\"}}", state.as_str()),
+                    (*state == status.state) as u8 as f64,
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "localitysrv_onion_state_changed_timestamp_seconds",
+        "gauge",
+        "When the onion service entered its current state.",
+        &[(String::new(), status.state_changed_at as f64)],
+    );
+    metric(
+        "localitysrv_tor_bootstrap_ratio",
+        "gauge",
+        "Tor client bootstrap progress, from 0 to 1.",
+        &[(String::new(), f64::from(status.bootstrap_progress) / 100.0)],
+    );
+    metric(
+        "localitysrv_onion_attempt",
+        "gauge",
+        "The current launch attempt of the onion service.",
+        &[(String::new(), f64::from(status.attempt))],
+    );
+    metric(
+        "localitysrv_onion_restarts_total",
+        "counter",
+        "Onion service relaunches after a failure.",
+        &[(String::new(), status.restarts as f64)],
+    );
+    metric(
+        "localitysrv_onion_reachability_losses_total",
+        "counter",
+        "Times the onion service went from reachable to unreachable.",
+        &[(String::new(), status.reachability_losses as f64)],
+    );
+    metric(
+        "localitysrv_onion_rejected_streams_total",
+        "counter",
+        "Onion service streams refused, by reason.",
+        &[
+            (
+                "{reason=\"unmapped_port\"}".to_string(),
+                status.rejected_streams.unmapped_port as f64,
+            ),
+            (
+                "{reason=\"stream_limit\"}".to_string(),
+                status.rejected_streams.stream_limit as f64,
+            ),
+        ],
+    );
+
+    out
+}
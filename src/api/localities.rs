@@ -75,8 +75,14 @@ pub async fn search_localities(
             let max_longitude = locality.max_longitude;
             let max_latitude = locality.max_latitude;
             let assets_dir = config.assets_dir.clone();
-            let onion_address = config.onion_address.clone();
+            let onion_base_url = config.onion_base_url();
+            let http_base_url = if config.serve_http {
+                config.http_base_url()
+            } else {
+                None
+            };
             let country_code_for_async = country_code_clone.clone();
+            let storage_service = app_state.storage_service.clone();
 
             async move {
                 let file_path = StdPath::new(&assets_dir)
@@ -86,15 +92,18 @@ pub async fn search_localities(
 
                 let file_size = match fs::metadata(&file_path).await {
                     Ok(metadata) => metadata.len(),
-                    Err(_) => 0,
+                    Err(_) => match storage_service.read_layout(&file_path).await {
+                        Ok(layout) => layout.length(),
+                        Err(_) => 0,
+                    },
                 };
 
-                let onion_link = format!(
-                    "http://{}/countries/{}/localities/{}/pmtiles",
-                    onion_address.unwrap(),
-                    country_code_for_async,
-                    id
+                let path = format!(
+                    "/countries/{}/localities/{}/pmtiles",
+                    country_code_for_async, id
                 );
+                let http_link = http_base_url.map(|base_url| format!("{}{}", base_url, path));
+                let onion_link = onion_base_url.map(|base_url| format!("{}{}", base_url, path));
 
                 LocalityInfo {
                     id,
@@ -108,6 +117,7 @@ pub async fn search_localities(
                     max_longitude,
                     max_latitude,
                     file_size,
+                    http_link,
                     onion_link,
                 }
             }
@@ -1,3 +1,8 @@
+pub mod admin;
 pub mod countries;
+pub mod health;
 pub mod localities;
+pub mod onion;
 pub mod pmtiles;
+pub mod regions;
+pub mod signing;
@@ -0,0 +1,47 @@
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use std::sync::{Arc, OnceLock};
+
+pub async fn get_onion(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    let config = app_state.config.lock().await;
+    let tor_status = app_state.tor_status.snapshot();
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "enabled": config.serve_onion,
+            "onion_address": config.onion_address,
+            "url": config.onion_base_url(),
+            "state": tor_status.service_state,
+            "reachable": tor_status.is_reachable()
+        }
+    }))
+}
+
+/// Advertises the onion address on clearnet responses, so Tor Browser offers
+/// to switch to the onion service for the same path.
+pub async fn onion_location_header(
+    State(onion_base_url): State<Arc<OnceLock<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let mut response = next.run(request).await;
+
+    if let Some(onion_base_url) = onion_base_url.get() {
+        if let Ok(value) = format!("{}{}", onion_base_url, path).parse() {
+            response.headers_mut().insert("Onion-Location", value);
+        }
+    }
+
+    response
+}
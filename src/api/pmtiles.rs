@@ -1,106 +1,413 @@
+use crate::api::signing::sign_archive_response;
+use crate::models::storage::ArchiveLayout;
+use crate::services::storage::StorageService;
 use crate::AppState;
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
     response::Response,
+    Json,
 };
-use std::path::PathBuf;
+use futures::StreamExt;
+use std::path::{Path as StdPath, PathBuf};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt},
 };
+use tracing::warn;
 
 pub async fn serve_pmtiles(
     State(app_state): State<AppState>,
     Path((country_code, id)): Path<(String, String)>,
     headers: HeaderMap,
 ) -> Result<Response<Body>, StatusCode> {
-    let config = app_state.config.lock().await;
-    let file_path = PathBuf::from(&config.assets_dir)
+    let (assets_dir, lazy_extraction, lazy_extraction_retry_after) = {
+        let config = app_state.config.lock().await;
+        (
+            config.assets_dir.clone(),
+            config.lazy_extraction,
+            config.lazy_extraction_retry_after,
+        )
+    };
+    let file_path = PathBuf::from(&assets_dir)
         .join("localities")
-        .join(country_code)
+        .join(&country_code)
         .join(format!("{}.pmtiles", id));
 
+    // The archive is opened before its hash is read: a re-extraction replaces the archive
+    // before its build record, so the hash is never older than the archive served
+    let archive_sha256 = || {
+        id.parse::<i64>().ok().and_then(|locality_id| {
+            app_state
+                .extraction_service
+                .locality_archive_sha256(&country_code, locality_id)
+                .map(|sha256| (locality_id, sha256))
+        })
+    };
+
     // Check if file exists and get its metadata
-    let metadata = match tokio::fs::metadata(&file_path).await {
-        Ok(metadata) => metadata,
+    let (file, metadata) = match File::open(&file_path).await {
+        Ok(file) => match file.metadata().await {
+            Ok(metadata) => (file, metadata),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        Err(_) if StorageService::layout_path(&file_path).exists() => {
+            let layout = match app_state.storage_service.read_layout(&file_path).await {
+                Ok(layout) => layout,
+                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+            let archive = archive_sha256();
+            app_state.budget_service.record_access(&file_path).await;
+            let mut response =
+                serve_virtual_archive(&app_state, &layout, &format!("{}.pmtiles", id), &headers)
+                    .await?;
+            if let Some((locality_id, sha256)) = &archive {
+                add_archive_headers(
+                    &app_state,
+                    &country_code,
+                    *locality_id,
+                    sha256,
+                    &mut response,
+                );
+            }
+            return Ok(response);
+        }
+        Err(_) if lazy_extraction => {
+            return request_lazy_extraction(
+                &app_state,
+                &country_code,
+                &id,
+                lazy_extraction_retry_after,
+            )
+            .await;
+        }
         Err(_) => return Err(StatusCode::NOT_FOUND),
     };
-    let file_size = metadata.len();
 
-    // Handle range requests (HTTP 206 Partial Content)
-    if let Some(range_header) = headers.get("Range") {
-        if let Ok(range_str) = range_header.to_str() {
-            // Parse range header: "bytes=start-end" or "bytes=start-"
-            if let Some(caps) = regex::Regex::new(r"bytes=(\d+)-(\d*)")
-                .unwrap()
-                .captures(range_str)
+    let archive = archive_sha256();
+    app_state.budget_service.record_access(&file_path).await;
+
+    let mut response =
+        serve_file(file, metadata.len(), &format!("{}.pmtiles", id), &headers).await?;
+    if let Some((locality_id, sha256)) = &archive {
+        add_archive_headers(
+            &app_state,
+            &country_code,
+            *locality_id,
+            sha256,
+            &mut response,
+        );
+    }
+    Ok(response)
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeltaQueryParams {
+    pub from: Option<String>,
+}
+
+/// Serves the patch from the archive version `from`, an ETag of a previous response, to
+/// the current archive. Falls back to the full archive when `from` is unknown.
+pub async fn serve_pmtiles_delta(
+    State(app_state): State<AppState>,
+    Path((country_code, id)): Path<(String, String)>,
+    Query(params): Query<DeltaQueryParams>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let from = params.from.as_deref().unwrap_or("").trim_matches('"');
+
+    let delta = match id.parse::<i64>() {
+        Ok(locality_id) => {
+            if app_state
+                .extraction_service
+                .locality_archive_sha256(&country_code, locality_id)
+                .is_some_and(|sha256| sha256 == from)
             {
-                let start: u64 = caps[1].parse().unwrap_or(0);
-                let end = if caps[2].is_empty() {
-                    file_size - 1
-                } else {
-                    caps[2].parse().unwrap_or(file_size - 1)
-                };
-
-                // Validate range
-                if start < file_size && end < file_size && start <= end {
-                    let content_length = end - start + 1;
-
-                    // Open file and seek to start position
-                    let mut file = match File::open(&file_path).await {
-                        Ok(file) => file,
-                        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-                    };
-
-                    match file.seek(std::io::SeekFrom::Start(start)).await {
-                        Ok(_) => {}
-                        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-                    }
-
-                    // Read the specific range
-                    let mut buffer = vec![0u8; content_length.try_into().unwrap_or(0)];
-                    match file.read_exact(&mut buffer).await {
-                        Ok(_) => {}
-                        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-                    }
-
-                    // Return partial content response
-                    return Ok(Response::builder()
-                        .status(StatusCode::PARTIAL_CONTENT)
-                        .header("Content-Type", "application/octet-stream")
-                        .header("Content-Length", content_length.to_string())
-                        .header(
-                            "Content-Range",
-                            format!("bytes {}-{}/{}", start, end, file_size),
-                        )
-                        .header("Accept-Ranges", "bytes")
-                        .body(Body::from(buffer))
-                        .unwrap());
+                return Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .header("ETag", format!("\"{}\"", from))
+                    .body(Body::empty())
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+            }
+
+            match app_state
+                .extraction_service
+                .locality_delta(&country_code, locality_id, from)
+                .await
+            {
+                Ok(delta) => delta.map(|(delta_path, to)| (delta_path, to, locality_id)),
+                Err(e) => {
+                    warn!("Failed to build delta for locality {}: {}", id, e);
+                    None
                 }
             }
         }
+        Err(_) => None,
+    };
+
+    let (delta_path, to, locality_id) = match delta {
+        Some(delta) => delta,
+        None => return serve_pmtiles(State(app_state), Path((country_code, id)), headers).await,
+    };
+
+    let metadata = match tokio::fs::metadata(&delta_path).await {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let mut response = serve_archive(
+        &delta_path,
+        metadata.len(),
+        &format!("{}.delta.pmtiles", id),
+        &headers,
+    )
+    .await?;
+
+    add_archive_headers(&app_state, &country_code, locality_id, &to, &mut response);
+    if let (Ok(from), Ok(to)) = (from.parse(), to.parse()) {
+        response.headers_mut().insert("X-Delta-From", from);
+        response.headers_mut().insert("X-Delta-To", to);
     }
 
-    // Full file response (HTTP 200)
-    let file = match File::open(&file_path).await {
+    Ok(response)
+}
+
+/// Adds the ETag of an archive, its SHA-256, and the signature of that hash to a response.
+fn add_archive_headers(
+    app_state: &AppState,
+    country_code: &str,
+    id: i64,
+    sha256: &str,
+    response: &mut Response<Body>,
+) {
+    if let Ok(etag) = format!("\"{}\"", sha256).parse() {
+        response.headers_mut().insert("ETag", etag);
+    }
+    sign_archive_response(app_state, country_code, id, sha256, response);
+}
+
+pub async fn serve_country_pmtiles(
+    State(app_state): State<AppState>,
+    Path(country_code): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let file_path = app_state
+        .extraction_service
+        .country_archive_path(&country_code);
+
+    let metadata = match tokio::fs::metadata(&file_path).await {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(StatusCode::NOT_FOUND),
+    };
+
+    app_state.budget_service.record_access(&file_path).await;
+
+    serve_archive(
+        &file_path,
+        metadata.len(),
+        &format!("{}.pmtiles", country_code),
+        &headers,
+    )
+    .await
+}
+
+/// Serves an archive in full or, for range requests, the requested byte range.
+pub async fn serve_archive(
+    file_path: &StdPath,
+    file_size: u64,
+    file_name: &str,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let file = match File::open(file_path).await {
         Ok(file) => file,
         Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
     };
 
+    serve_file(file, file_size, file_name, headers).await
+}
+
+/// Serves an opened archive in full or, for range requests, the requested byte range.
+async fn serve_file(
+    mut file: File,
+    file_size: u64,
+    file_name: &str,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    // Handle range requests (HTTP 206 Partial Content)
+    if let Some((start, end)) = parse_range(headers, file_size) {
+        let content_length = end - start + 1;
+
+        // Seek to start position
+        match file.seek(std::io::SeekFrom::Start(start)).await {
+            Ok(_) => {}
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+
+        // Stream the specific range
+        let stream = tokio_util::io::ReaderStream::new(file.take(content_length));
+
+        return Ok(partial_content_response(
+            Body::from_stream(stream),
+            start,
+            end,
+            file_size,
+        ));
+    }
+
+    // Full file response (HTTP 200)
     let stream = tokio_util::io::ReaderStream::new(file);
-    let body = Body::from_stream(stream);
 
-    Ok(Response::builder()
+    Ok(full_content_response(
+        Body::from_stream(stream),
+        file_size,
+        file_name,
+    ))
+}
+
+/// Serves an archive whose tiles live in the deduplicated tile store.
+async fn serve_virtual_archive(
+    app_state: &AppState,
+    layout: &ArchiveLayout,
+    file_name: &str,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let file_size = layout.length();
+
+    if let Some((start, end)) = parse_range(headers, file_size) {
+        let stream = app_state.storage_service.stream_range(layout, start, end);
+
+        return Ok(partial_content_response(
+            Body::from_stream(stream),
+            start,
+            end,
+            file_size,
+        ));
+    }
+
+    let stream =
+        futures::stream::iter(app_state.storage_service.blob_paths(layout)).then(tokio::fs::read);
+
+    Ok(full_content_response(
+        Body::from_stream(stream),
+        file_size,
+        file_name,
+    ))
+}
+
+/// Parses a `bytes=start-end` or `bytes=start-` range header into a valid inclusive range.
+fn parse_range(headers: &HeaderMap, file_size: u64) -> Option<(u64, u64)> {
+    let range_str = headers.get("Range")?.to_str().ok()?;
+    let caps = regex::Regex::new(r"bytes=(\d+)-(\d*)")
+        .unwrap()
+        .captures(range_str)?;
+
+    let start: u64 = caps[1].parse().unwrap_or(0);
+    let end = if caps[2].is_empty() {
+        file_size.checked_sub(1)?
+    } else {
+        caps[2].parse().unwrap_or(file_size.checked_sub(1)?)
+    };
+
+    // Validate range
+    if start < file_size && end < file_size && start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn partial_content_response(body: Body, start: u64, end: u64, file_size: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Length", (end - start + 1).to_string())
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end, file_size),
+        )
+        .header("Accept-Ranges", "bytes")
+        .body(body)
+        .unwrap()
+}
+
+fn full_content_response(body: Body, file_size: u64, file_name: &str) -> Response<Body> {
+    Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/octet-stream")
         .header("Content-Length", file_size.to_string())
         .header("Accept-Ranges", "bytes")
         .header(
             "Content-Disposition",
-            format!("attachment; filename=\"{}.pmtiles\"", id),
+            format!("attachment; filename=\"{}\"", file_name),
         )
         .body(body)
+        .unwrap()
+}
+
+async fn request_lazy_extraction(
+    app_state: &AppState,
+    country_code: &str,
+    id: &str,
+    retry_after: u64,
+) -> Result<Response<Body>, StatusCode> {
+    let locality_id: i64 = id.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let status = match app_state
+        .extraction_service
+        .request_locality_extraction(country_code, locality_id)
+        .await
+    {
+        Ok(Some(status)) => status,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let status_url = format!(
+        "/countries/{}/localities/{}/pmtiles/status",
+        country_code, locality_id
+    );
+
+    let body = serde_json::json!({
+        "success": true,
+        "data": {
+            "extraction": status,
+            "status_url": status_url
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .header("Retry-After", retry_after.to_string())
+        .header("Location", status_url)
+        .body(Body::from(body.to_string()))
         .unwrap())
 }
+
+pub async fn get_pmtiles_status(
+    State(app_state): State<AppState>,
+    Path((country_code, id)): Path<(String, String)>,
+) -> Json<serde_json::Value> {
+    let locality_id: i64 = match id.parse() {
+        Ok(locality_id) => locality_id,
+        Err(_) => {
+            return Json(serde_json::json!({
+                "success": false,
+                "error": "Invalid locality id"
+            }));
+        }
+    };
+
+    let status = app_state
+        .extraction_service
+        .get_locality_extraction_status(&country_code, locality_id)
+        .await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "extraction": status
+        }
+    }))
+}
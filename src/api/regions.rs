@@ -0,0 +1,90 @@
+use crate::api::pmtiles::serve_archive;
+use crate::models::extraction::ExtractionStatus;
+use crate::AppState;
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Json,
+};
+
+#[derive(serde::Deserialize)]
+pub struct RegionRequest {
+    pub bbox: Option<[f64; 4]>,
+    pub geometry: Option<serde_json::Value>,
+}
+
+pub async fn create_region(
+    State(app_state): State<AppState>,
+    Json(request): Json<RegionRequest>,
+) -> Json<serde_json::Value> {
+    match app_state
+        .extraction_service
+        .request_region_extraction(request.bbox, request.geometry)
+        .await
+    {
+        Ok(job_id) => Json(serde_json::json!({
+            "success": true,
+            "data": {
+                "job_id": job_id,
+                "extraction": ExtractionStatus::Pending,
+                "status_url": format!("/regions/{}", job_id)
+            }
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "error": e.to_string()
+        })),
+    }
+}
+
+pub async fn get_region(
+    State(app_state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Json<serde_json::Value> {
+    let status = app_state
+        .extraction_service
+        .get_region_extraction_status(&job_id)
+        .await;
+
+    let archive_url = match status {
+        ExtractionStatus::Ready => Some(format!("/regions/{}/pmtiles", job_id)),
+        _ => None,
+    };
+
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "job_id": job_id,
+            "extraction": status,
+            "archive_url": archive_url
+        }
+    }))
+}
+
+pub async fn serve_region_pmtiles(
+    State(app_state): State<AppState>,
+    Path(job_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let file_path = app_state
+        .extraction_service
+        .region_archive_path(&job_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let metadata = match tokio::fs::metadata(&file_path).await {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(StatusCode::NOT_FOUND),
+    };
+
+    app_state.budget_service.record_access(&file_path).await;
+
+    serve_archive(
+        &file_path,
+        metadata.len(),
+        &format!("{}.pmtiles", job_id),
+        &headers,
+    )
+    .await
+}
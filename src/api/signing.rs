@@ -0,0 +1,34 @@
+use crate::services::signing::ARCHIVE_MESSAGE_FORMAT;
+use crate::AppState;
+use axum::{body::Body, extract::State, response::Response, Json};
+
+pub async fn get_public_key(State(app_state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "success": true,
+        "data": {
+            "algorithm": "ed25519",
+            "public_key": app_state.signing_service.public_key(),
+            "archive_message": ARCHIVE_MESSAGE_FORMAT
+        }
+    }))
+}
+
+/// Adds the SHA-256 of a whole archive and its detached signature to a response, for both
+/// full and range responses.
+pub fn sign_archive_response(
+    app_state: &AppState,
+    country_code: &str,
+    id: i64,
+    sha256: &str,
+    response: &mut Response<Body>,
+) {
+    let signature = app_state
+        .signing_service
+        .sign_archive(country_code, id, sha256);
+    let headers = response.headers_mut();
+
+    if let (Ok(sha256), Ok(signature)) = (sha256.parse(), signature.parse()) {
+        headers.insert("X-Archive-SHA256", sha256);
+        headers.insert("X-Signature", signature);
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -16,6 +17,75 @@ pub struct Args {
 
     #[arg(long)]
     pub no_extract: bool,
+
+    #[arg(long)]
+    pub update: bool,
+
+    #[arg(long)]
+    pub prune: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Remove archives of localities that are no longer in the database
+    Prune {
+        #[arg(long)]
+        dry_run: bool,
+
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Package countries into a tar/zstd bundle for offline distribution
+    Export {
+        #[arg(required = true)]
+        countries: Vec<String>,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Seed the assets directory from a bundle
+    Import { bundle: PathBuf },
+    /// Export or import the onion service identity key
+    OnionKey {
+        /// Manage the key of the admin onion service instead
+        #[arg(long, global = true)]
+        admin: bool,
+
+        #[command(subcommand)]
+        action: OnionKeyAction,
+    },
+    /// Manage the clients authorized to discover the onion service
+    ClientAuth {
+        /// Manage the clients of the admin onion service instead
+        #[arg(long, global = true)]
+        admin: bool,
+
+        #[command(subcommand)]
+        action: ClientAuthAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ClientAuthAction {
+    Add { nickname: String, key: String },
+    List,
+    Revoke { nickname: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OnionKeyAction {
+    Export {
+        output: PathBuf,
+    },
+    Import {
+        key: PathBuf,
+
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 impl Args {
@@ -1,42 +1,248 @@
+use crate::models::extraction::ExtractionProfile;
 use dotenvy::dotenv;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid {0}: {1}")]
+    InvalidValue(&'static str, String),
+    #[error("Failed to load extraction profiles from {0}: {1}")]
+    UnreadableProfiles(String, String),
+}
+
+#[derive(Clone, Debug)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Sockets passed in by systemd socket activation
+    Systemd,
+}
+
+/// The router served on an onion service virtual port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnionRoute {
+    /// The public read API
+    Public,
+    /// The admin API, served by a separate onion service with its own clients
+    Admin,
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(address) => write!(f, "http://{}", address),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            BindAddress::Systemd => write!(f, "systemd"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
     pub server_port: u16,
+    pub serve_http: bool,
+    pub serve_onion: bool,
+    pub public_http_url: Option<String>,
+    pub bind_addresses: Vec<BindAddress>,
+    pub unix_socket_mode: u32,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub admin_port: Option<u16>,
     pub assets_dir: String,
     pub pmtiles_cmd: String,
     pub bzip2_cmd: String,
     pub find_cmd: String,
+    pub df_cmd: String,
+    pub tile_join_cmd: String,
+    pub tar_cmd: String,
     pub whosonfirst_db_url: String,
     pub protomaps_builds_url: String,
+    pub protomaps_base_url: String,
+    pub protomaps_build: Option<String>,
     pub planet_pmtiles_path: Option<String>,
     pub target_countries: Vec<String>,
     pub max_concurrent_extractions: usize,
     pub db_connection_pool_size: u32,
+    pub lazy_extraction: bool,
+    pub lazy_extraction_retry_after: u64,
+    pub archive_max_age_days: Option<u64>,
+    pub extraction_profile: ExtractionProfile,
+    pub country_profiles: HashMap<String, ExtractionProfile>,
+    pub max_region_area_km2: f64,
+    pub max_concurrent_region_extractions: usize,
+    /// Region extractions waiting or running before new requests are refused
+    pub max_queued_regions: usize,
+    /// `None` keeps region archives forever
+    pub region_archive_ttl_secs: Option<u64>,
+    pub dedup_storage: bool,
+    pub max_assets_bytes: Option<u64>,
+    /// Free disk space required by the startup preflight before extracting
+    pub min_free_disk_bytes: u64,
+    pub evict_archives: bool,
+    pub tor_state_dir: Option<String>,
+    pub tor_cache_dir: Option<String>,
+    pub expected_onion_address: Option<String>,
+    pub restricted_discovery: bool,
+    pub onion_client_auth_dir: Option<String>,
+    pub onion_admin_client_auth_dir: Option<String>,
+    pub onion_ports: Vec<(u16, OnionRoute)>,
+    pub onion_pow: bool,
+    /// INTRODUCE2 requests per second accepted at each introduction point
+    pub onion_intro_rate_limit: Option<u32>,
+    pub onion_intro_burst: Option<u32>,
+    /// `0` allows any number of concurrent streams
+    pub onion_max_streams_per_circuit: usize,
+    /// Bytes per second sent on each circuit
+    pub onion_circuit_bandwidth: Option<u64>,
+    /// `None` retries forever
+    pub tor_max_retries: Option<u32>,
+    pub tor_retry_base_delay_secs: u64,
+    pub tor_retry_max_delay_secs: u64,
     pub onion_address: Option<String>,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, env::VarError> {
+    pub fn from_env() -> Result<Self, ConfigError> {
         dotenv().ok();
 
-        Ok(Self {
-            server_port: env::var("SERVER_PORT")
-                .unwrap_or_else(|_| "8080".to_string())
-                .parse()
-                .unwrap_or(8080),
+        let serve_modes = env::var("SERVE_MODES").unwrap_or_else(|_| "http,onion".to_string());
+        let (mut serve_http, mut serve_onion) = (false, false);
+        for mode in serve_modes.split(',').map(|s| s.trim().to_lowercase()) {
+            match mode.as_str() {
+                "http" => serve_http = true,
+                "onion" => serve_onion = true,
+                "" => {}
+                _ => return Err(ConfigError::InvalidValue("SERVE_MODES", serve_modes)),
+            }
+        }
+        if !serve_http && !serve_onion {
+            return Err(ConfigError::InvalidValue("SERVE_MODES", serve_modes));
+        }
+
+        let server_port = env::var("SERVER_PORT")
+            .unwrap_or_else(|_| "8080".to_string())
+            .parse()
+            .unwrap_or(8080);
+
+        let mut bind_addresses: Vec<BindAddress> = env::var("BIND_ADDRESSES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                parse_bind_address(s, server_port)
+                    .ok_or_else(|| ConfigError::InvalidValue("BIND_ADDRESSES", s.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        if bind_addresses.is_empty() {
+            bind_addresses.push(BindAddress::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                server_port,
+            )));
+        }
+
+        let restricted_discovery = env::var("ONION_RESTRICTED_DISCOVERY")
+            .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+
+        let mut onion_ports: Vec<(u16, OnionRoute)> = Vec::new();
+        for entry in env::var("ONION_PORTS")
+            .unwrap_or_else(|_| "80=public".to_string())
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+        {
+            let (port, route) = parse_onion_port(entry)
+                .ok_or_else(|| ConfigError::InvalidValue("ONION_PORTS", entry.to_string()))?;
+            // Public and admin ports belong to different onion services, so only a port
+            // mapped twice on the same service is ambiguous
+            if onion_ports.contains(&(port, route)) {
+                return Err(ConfigError::InvalidValue(
+                    "ONION_PORTS",
+                    format!("port {} is mapped twice", port),
+                ));
+            }
+            onion_ports.push((port, route));
+        }
+        if onion_ports.is_empty() {
+            return Err(ConfigError::InvalidValue(
+                "ONION_PORTS",
+                "no port is mapped".to_string(),
+            ));
+        }
+
+        let tls_cert_path = env::var("TLS_CERT_PATH").ok().filter(|s| !s.is_empty());
+        let tls_key_path = env::var("TLS_KEY_PATH").ok().filter(|s| !s.is_empty());
+        match (&tls_cert_path, &tls_key_path) {
+            (Some(_), None) => {
+                return Err(ConfigError::InvalidValue(
+                    "TLS_KEY_PATH",
+                    "unset while TLS_CERT_PATH is set".to_string(),
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::InvalidValue(
+                    "TLS_CERT_PATH",
+                    "unset while TLS_KEY_PATH is set".to_string(),
+                ))
+            }
+            _ => {}
+        }
+
+        let tor_retry_base_delay_secs = env::var("TOR_RETRY_BASE_DELAY_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&secs| secs > 0)
+            .unwrap_or(5);
+
+        let country_profiles = match env::var("EXTRACTION_PROFILES_PATH")
+            .ok()
+            .filter(|s| !s.is_empty())
+        {
+            Some(path) => load_country_profiles(&path)?,
+            None => HashMap::new(),
+        };
+
+        let config = Self {
+            server_port,
+            serve_http,
+            serve_onion,
+            public_http_url: env::var("PUBLIC_HTTP_URL")
+                .ok()
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty()),
+            bind_addresses,
+            unix_socket_mode: env::var("UNIX_SOCKET_MODE")
+                .ok()
+                .and_then(|s| u32::from_str_radix(s.trim(), 8).ok())
+                .unwrap_or(0o660),
+            tls_cert_path,
+            tls_key_path,
+            admin_port: env::var("ADMIN_PORT").ok().and_then(|s| s.parse().ok()),
             assets_dir: env::var("ASSETS_DIR").unwrap_or_else(|_| "./assets".to_string()),
             pmtiles_cmd: env::var("PMTILES_CMD").unwrap_or_else(|_| "pmtiles".to_string()),
             bzip2_cmd: env::var("BZIP2_CMD").unwrap_or_else(|_| "bzip2".to_string()),
             find_cmd: env::var("FIND_CMD").unwrap_or_else(|_| "find".to_string()),
+            df_cmd: env::var("DF_CMD").unwrap_or_else(|_| "df".to_string()),
+            tile_join_cmd: env::var("TILE_JOIN_CMD").unwrap_or_else(|_| "tile-join".to_string()),
+            tar_cmd: env::var("TAR_CMD").unwrap_or_else(|_| "tar".to_string()),
             whosonfirst_db_url: env::var("WHOSEONFIRST_DB_URL").unwrap_or_else(|_| {
                 "https://data.geocode.earth/wof/dist/sqlite/whosonfirst-data-admin-latest.db.bz2"
                     .to_string()
             }),
             protomaps_builds_url: env::var("PROTOMAPS_BUILDS_URL")
                 .unwrap_or_else(|_| "https://build-metadata.protomaps.dev/builds.json".to_string()),
+            protomaps_base_url: env::var("PROTOMAPS_BASE_URL")
+                .unwrap_or_else(|_| "https://build.protomaps.com".to_string()),
+            protomaps_build: env::var("PROTOMAPS_BUILD")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
             planet_pmtiles_path: env::var("PLANET_PMTILES_PATH")
                 .ok()
                 .filter(|s| !s.is_empty()),
@@ -54,8 +260,152 @@ impl Config {
                 .unwrap_or_else(|_| "10".to_string())
                 .parse()
                 .unwrap_or(10),
+            lazy_extraction: env::var("LAZY_EXTRACTION")
+                .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            lazy_extraction_retry_after: env::var("LAZY_EXTRACTION_RETRY_AFTER")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or(10),
+            archive_max_age_days: env::var("ARCHIVE_MAX_AGE_DAYS")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            extraction_profile: ExtractionProfile {
+                min_zoom: parse_optional("MIN_ZOOM")?,
+                max_zoom: parse_optional("MAX_ZOOM")?,
+                layers: env::var("LAYERS")
+                    .unwrap_or_else(|_| "".to_string())
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+            },
+            country_profiles,
+            max_region_area_km2: env::var("MAX_REGION_AREA_KM2")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .unwrap_or(10000.0),
+            max_concurrent_region_extractions: env::var("MAX_CONCURRENT_REGION_EXTRACTIONS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&count| count > 0)
+                .unwrap_or(2),
+            max_queued_regions: env::var("MAX_QUEUED_REGIONS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(16),
+            region_archive_ttl_secs: env::var("REGION_ARCHIVE_TTL_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .or(Some(24 * 60 * 60))
+                .filter(|&secs| secs > 0),
+            dedup_storage: env::var("STORAGE_MODE")
+                .map(|s| s.trim().eq_ignore_ascii_case("dedup"))
+                .unwrap_or(false),
+            max_assets_bytes: env::var("MAX_ASSETS_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            min_free_disk_bytes: env::var("MIN_FREE_DISK_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1024 * 1024 * 1024),
+            evict_archives: env::var("EVICT_ARCHIVES")
+                .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            tor_state_dir: env::var("TOR_STATE_DIR").ok().filter(|s| !s.is_empty()),
+            tor_cache_dir: env::var("TOR_CACHE_DIR").ok().filter(|s| !s.is_empty()),
+            expected_onion_address: env::var("ONION_ADDRESS")
+                .ok()
+                .map(|s| normalize_onion_address(&s))
+                .filter(|s| !s.is_empty()),
+            restricted_discovery,
+            onion_client_auth_dir: env::var("ONION_CLIENT_AUTH_DIR")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            onion_admin_client_auth_dir: env::var("ONION_ADMIN_CLIENT_AUTH_DIR")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            onion_ports,
+            onion_pow: env::var("ONION_POW")
+                .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            onion_intro_rate_limit: env::var("ONION_INTRO_RATE_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&rate| rate > 0),
+            onion_intro_burst: env::var("ONION_INTRO_BURST")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&burst| burst > 0),
+            onion_max_streams_per_circuit: env::var("ONION_MAX_STREAMS_PER_CIRCUIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(32),
+            onion_circuit_bandwidth: env::var("ONION_CIRCUIT_BANDWIDTH")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&rate| rate > 0),
+            tor_max_retries: parse_optional("TOR_MAX_RETRIES")?,
+            tor_retry_base_delay_secs,
+            // A cap below the base delay would retry in a hot loop
+            tor_retry_max_delay_secs: env::var("TOR_RETRY_MAX_DELAY_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(300)
+                .max(tor_retry_base_delay_secs),
             onion_address: None,
-        })
+        };
+
+        config.validate_extraction_profiles()?;
+
+        Ok(config)
+    }
+
+    /// Rejects profiles whose zoom range, once merged with the global settings, is empty.
+    fn validate_extraction_profiles(&self) -> Result<(), ConfigError> {
+        let mut profiles = vec![(
+            "MIN_ZOOM/MAX_ZOOM".to_string(),
+            self.extraction_profile.clone(),
+        )];
+        for country_code in self.country_profiles.keys() {
+            profiles.push((
+                format!("extraction profile of {}", country_code),
+                self.extraction_profile(country_code),
+            ));
+        }
+
+        for (name, profile) in profiles {
+            if let (Some(min_zoom), Some(max_zoom)) = (profile.min_zoom, profile.max_zoom) {
+                if min_zoom > max_zoom {
+                    return Err(ConfigError::InvalidValue(
+                        "zoom range",
+                        format!(
+                            "{} has min_zoom {} above max_zoom {}",
+                            name, min_zoom, max_zoom
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The extraction profile of a country, where per-country settings override the global ones.
+    pub fn extraction_profile(&self, country_code: &str) -> ExtractionProfile {
+        merge_extraction_profile(
+            &self.extraction_profile,
+            self.country_profiles.get(country_code),
+        )
+    }
+
+    /// Whether any extraction profile filters vector layers, which requires `tile-join`.
+    pub fn filters_layers(&self) -> bool {
+        !self.extraction_profile.layers.is_empty()
+            || self
+                .country_profiles
+                .values()
+                .any(|profile| !profile.layers.is_empty())
     }
 
     pub fn database_path(&self) -> PathBuf {
@@ -70,7 +420,288 @@ impl Config {
         PathBuf::from(&self.assets_dir).join("country-codes.json")
     }
 
+    pub fn protomaps_builds_cache_path(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("protomaps-builds.json")
+    }
+
+    pub fn signing_key_path(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("signing.key")
+    }
+
+    /// The base URL of `http_link`s, defaulting to the first TCP listener.
+    /// Without `PUBLIC_HTTP_URL`, Unix socket and systemd listeners have no
+    /// URL to advertise.
+    pub fn http_base_url(&self) -> Option<String> {
+        if let Some(url) = &self.public_http_url {
+            return Some(url.clone());
+        }
+
+        let scheme = if self.tls_enabled() { "https" } else { "http" };
+        self.bind_addresses
+            .iter()
+            .find_map(|address| match address {
+                BindAddress::Tcp(address) if address.ip().is_unspecified() => {
+                    Some(format!("{}://127.0.0.1:{}", scheme, address.port()))
+                }
+                BindAddress::Tcp(address) => Some(format!("{}://{}", scheme, address)),
+                _ => None,
+            })
+    }
+
+    /// The onion URL of the public API, `None` until the onion service has launched.
+    pub fn onion_base_url(&self) -> Option<String> {
+        let address = self.onion_address.as_ref()?;
+        let port = self
+            .onion_ports
+            .iter()
+            .find(|(_, route)| *route == OnionRoute::Public)
+            .map(|(port, _)| *port)?;
+
+        Some(match port {
+            80 => format!("http://{}", address),
+            port => format!("http://{}:{}", address, port),
+        })
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert_path.is_some() && self.tls_key_path.is_some()
+    }
+
+    pub fn tor_state_dir(&self) -> PathBuf {
+        match &self.tor_state_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(&self.assets_dir).join("tor").join("state"),
+        }
+    }
+
+    pub fn tor_cache_dir(&self) -> PathBuf {
+        match &self.tor_cache_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(&self.assets_dir).join("tor").join("cache"),
+        }
+    }
+
+    pub fn onion_admin_client_auth_dir(&self) -> PathBuf {
+        match &self.onion_admin_client_auth_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(&self.assets_dir)
+                .join("tor")
+                .join("authorized_admin_clients"),
+        }
+    }
+
+    pub fn onion_client_auth_dir(&self) -> PathBuf {
+        match &self.onion_client_auth_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(&self.assets_dir)
+                .join("tor")
+                .join("authorized_clients"),
+        }
+    }
+
+    pub fn countries_dir(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("countries")
+    }
+
+    pub fn tiles_dir(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("tiles")
+    }
+
+    pub fn quarantine_dir(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("quarantine")
+    }
+
+    pub fn regions_dir(&self) -> PathBuf {
+        PathBuf::from(&self.assets_dir).join("regions")
+    }
+
     pub fn localities_dir(&self) -> PathBuf {
         PathBuf::from(&self.assets_dir).join("localities")
     }
 }
+
+fn load_country_profiles(path: &str) -> Result<HashMap<String, ExtractionProfile>, ConfigError> {
+    std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        .map_err(|e| ConfigError::UnreadableProfiles(path.to_string(), e))
+}
+
+/// Merges a country profile over the global one: each zoom bound and the layer list
+/// falls back to the global setting when the country leaves it unset.
+fn merge_extraction_profile(
+    global: &ExtractionProfile,
+    country: Option<&ExtractionProfile>,
+) -> ExtractionProfile {
+    match country {
+        Some(profile) => ExtractionProfile {
+            min_zoom: profile.min_zoom.or(global.min_zoom),
+            max_zoom: profile.max_zoom.or(global.max_zoom),
+            layers: if profile.layers.is_empty() {
+                global.layers.clone()
+            } else {
+                profile.layers.clone()
+            },
+        },
+        None => global.clone(),
+    }
+}
+
+/// Parses an optional variable, failing on a value that is set but malformed.
+fn parse_optional<T: std::str::FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match env::var(name).ok().map(|s| s.trim().to_string()) {
+        Some(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidValue(name, value)),
+        _ => Ok(None),
+    }
+}
+
+/// Parses `host:port`, a bare IP (listening on `SERVER_PORT`), `unix:<path>`
+/// or `systemd`.
+fn parse_bind_address(value: &str, default_port: u16) -> Option<BindAddress> {
+    if value.eq_ignore_ascii_case("systemd") {
+        return Some(BindAddress::Systemd);
+    }
+
+    if let Some(path) = value.strip_prefix("unix:") {
+        return (!path.is_empty()).then(|| BindAddress::Unix(PathBuf::from(path)));
+    }
+
+    if let Ok(address) = value.parse::<SocketAddr>() {
+        return Some(BindAddress::Tcp(address));
+    }
+
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| BindAddress::Tcp(SocketAddr::new(ip, default_port)))
+}
+
+/// Parses `<port>=<public|admin>`.
+fn parse_onion_port(value: &str) -> Option<(u16, OnionRoute)> {
+    let (port, route) = value.split_once('=')?;
+    let port = port.trim().parse().ok().filter(|&port: &u16| port > 0)?;
+    let route = match route.trim().to_lowercase().as_str() {
+        "public" => OnionRoute::Public,
+        "admin" => OnionRoute::Admin,
+        _ => return None,
+    };
+    Some((port, route))
+}
+
+/// Accepts an onion address with or without scheme and trailing slash.
+fn normalize_onion_address(address: &str) -> String {
+    let address = address.trim().to_lowercase();
+    let address = address
+        .strip_prefix("http://")
+        .or_else(|| address.strip_prefix("https://"))
+        .unwrap_or(&address);
+    address.trim_end_matches('/').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_addresses_are_parsed() {
+        assert!(matches!(
+            parse_bind_address("0.0.0.0:8000", 8080),
+            Some(BindAddress::Tcp(address)) if address == "0.0.0.0:8000".parse().unwrap()
+        ));
+        assert!(matches!(
+            parse_bind_address("[::1]:8000", 8080),
+            Some(BindAddress::Tcp(address)) if address == "[::1]:8000".parse().unwrap()
+        ));
+        assert!(matches!(
+            parse_bind_address("127.0.0.1", 8080),
+            Some(BindAddress::Tcp(address)) if address == "127.0.0.1:8080".parse().unwrap()
+        ));
+        assert!(matches!(
+            parse_bind_address("[::1]", 8080),
+            Some(BindAddress::Tcp(address)) if address == "[::1]:8080".parse().unwrap()
+        ));
+        assert!(matches!(
+            parse_bind_address("unix:/run/localitysrv/http.sock", 8080),
+            Some(BindAddress::Unix(path)) if path == std::path::Path::new("/run/localitysrv/http.sock")
+        ));
+        assert!(matches!(
+            parse_bind_address("SystemD", 8080),
+            Some(BindAddress::Systemd)
+        ));
+    }
+
+    #[test]
+    fn onion_ports_are_parsed() {
+        assert_eq!(
+            parse_onion_port("80=public"),
+            Some((80, OnionRoute::Public))
+        );
+        assert_eq!(
+            parse_onion_port(" 8001 = Admin "),
+            Some((8001, OnionRoute::Admin))
+        );
+        for value in [
+            "80",
+            "=public",
+            "0=public",
+            "65536=public",
+            "80=private",
+            "x=admin",
+        ] {
+            assert_eq!(parse_onion_port(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn country_profiles_override_the_global_profile() {
+        let global = ExtractionProfile {
+            min_zoom: Some(2),
+            max_zoom: Some(14),
+            layers: vec!["roads".to_string(), "places".to_string()],
+        };
+
+        assert_eq!(merge_extraction_profile(&global, None), global);
+        assert_eq!(
+            merge_extraction_profile(&global, Some(&ExtractionProfile::default())),
+            global
+        );
+
+        let country: ExtractionProfile =
+            serde_json::from_str(r#"{"max_zoom": 12, "layers": ["water"]}"#).unwrap();
+        assert_eq!(
+            merge_extraction_profile(&global, Some(&country)),
+            ExtractionProfile {
+                min_zoom: Some(2),
+                max_zoom: Some(12),
+                layers: vec!["water".to_string()],
+            }
+        );
+
+        let country = ExtractionProfile {
+            min_zoom: Some(5),
+            ..ExtractionProfile::default()
+        };
+        assert_eq!(
+            merge_extraction_profile(&ExtractionProfile::default(), Some(&country)),
+            country
+        );
+    }
+
+    #[test]
+    fn invalid_bind_addresses_are_rejected() {
+        for value in [
+            "localhost:8000",
+            "0.0.0.0:http",
+            "unix:",
+            "1.2.3.4:99999",
+            "tcp",
+        ] {
+            assert!(parse_bind_address(value, 8080).is_none(), "{}", value);
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use crate::cli::Args;
 use crate::config::Config;
+use crate::models::extraction::{CountryArchiveCheck, PruneMode};
 use crate::services::{
-    country::CountryService, database::DatabaseService, extraction::ExtractionService,
+    budget::BudgetService, country::CountryService, extraction::ExtractionService,
 };
-use std::collections::HashMap;
 use std::io::{self, Write};
 use std::path::Path;
 use tracing::{info, warn};
@@ -34,23 +34,61 @@ async fn download_and_decompress_database(
 
 async fn extract_missing_localities(
     extraction_service: &ExtractionService,
-    results: Vec<(&String, &String, u32, u32, bool)>,
+    checks: &[CountryArchiveCheck],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let missing_country_codes: Vec<String> = results
-        .into_iter()
-        .filter(|(_, _, _, _, is_complete)| !is_complete)
-        .map(|(country_code, _, _, _, _)| (*country_code).clone())
-        .collect();
-
-    if !missing_country_codes.is_empty() {
+    if checks.iter().any(|check| !check.is_complete()) {
         extraction_service
-            .extract_localities(&missing_country_codes)
+            .extract_missing_localities(checks)
             .await?;
         info!("Extraction completed.");
     }
     Ok(())
 }
 
+pub async fn prune_orphaned_archives(
+    extraction_service: &ExtractionService,
+    mode: PruneMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Looking for orphaned locality archives...");
+
+    let reports = extraction_service.prune_orphaned_archives(mode).await?;
+
+    if reports.is_empty() {
+        info!("✓ No orphaned archives found");
+        return Ok(());
+    }
+
+    info!("Country Code | Orphans  | Size (MB)");
+    info!("-------------|----------|----------");
+
+    for report in &reports {
+        info!(
+            "{:12} | {:8} | {:9.2}",
+            report.country_code,
+            report.orphans.len(),
+            report.bytes as f64 / 1_048_576.0
+        );
+        info!("Orphaned localities: {:?}", report.orphans);
+    }
+
+    let total_orphans: usize = reports.iter().map(|report| report.orphans.len()).sum();
+    let total_bytes: u64 = reports.iter().map(|report| report.bytes).sum();
+
+    let action = match mode {
+        PruneMode::DryRun => "found (dry run, nothing removed)",
+        PruneMode::Delete => "deleted",
+        PruneMode::Quarantine => "quarantined",
+    };
+    info!(
+        "{} orphaned archives {}, {:.2} MB",
+        total_orphans,
+        action,
+        total_bytes as f64 / 1_048_576.0
+    );
+
+    Ok(())
+}
+
 pub async fn ensure_tools_are_present(tools: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
     use crate::utils::cmd::ensure_tools_are_present as check_tools;
 
@@ -107,11 +145,65 @@ pub async fn ensure_database_is_present(
     Err("Database is missing and download is disabled".into())
 }
 
+/// Reports disk usage before extracting. Returns `false` when less than `MIN_FREE_DISK_BYTES`
+/// is available, or when the disk budget is exhausted and cannot be reclaimed by eviction.
+async fn check_disk_space(config: &Config, budget_service: &BudgetService) -> bool {
+    let usage = budget_service.usage();
+
+    let available = match crate::utils::file::available_disk_space(
+        &config.df_cmd,
+        Path::new(&config.assets_dir),
+    )
+    .await
+    {
+        Ok(available) => available,
+        Err(e) => {
+            warn!("Failed to check available disk space: {}", e);
+            return true;
+        }
+    };
+
+    info!(
+        "Disk usage: {:.2} MB used by assets, {:.2} MB available",
+        usage as f64 / 1_048_576.0,
+        available as f64 / 1_048_576.0
+    );
+
+    if available < config.min_free_disk_bytes {
+        warn!(
+            "✗ Not enough disk space: {:.2} MB available, {:.2} MB required by MIN_FREE_DISK_BYTES",
+            available as f64 / 1_048_576.0,
+            config.min_free_disk_bytes as f64 / 1_048_576.0
+        );
+        return false;
+    }
+
+    match budget_service.max_bytes() {
+        Some(max_bytes) if usage >= max_bytes && !budget_service.evicts() => {
+            warn!(
+                "✗ Disk budget exhausted: {:.2} MB of {:.2} MB used",
+                usage as f64 / 1_048_576.0,
+                max_bytes as f64 / 1_048_576.0
+            );
+            false
+        }
+        Some(max_bytes) if max_bytes.saturating_sub(usage) > available => {
+            warn!("Remaining disk budget exceeds the available disk space");
+            true
+        }
+        None => {
+            warn!("No disk budget set with MAX_ASSETS_BYTES, extraction may fill the disk");
+            true
+        }
+        _ => true,
+    }
+}
+
 pub async fn ensure_all_localities_present(
     extraction_service: &ExtractionService,
     country_service: &CountryService,
+    budget_service: &BudgetService,
     config: &Config,
-    db_service: &DatabaseService,
     args: &Args,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Checking localities extraction status...");
@@ -123,53 +215,24 @@ pub async fn ensure_all_localities_present(
         return Ok(());
     }
 
-    info!("Counting pmtiles files...");
-    let file_count_map = extraction_service
-        .batch_get_pmtiles_file_count(&countries_to_check)
+    info!("Comparing localities in the database with pmtiles files...");
+    let checks = extraction_service
+        .batch_check_country_archives(&countries_to_check)
         .await?;
 
-    info!("Querying database for locality counts...");
-    let mut db_count_map = HashMap::new();
-
-    for country_code in &countries_to_check {
-        let db_count = db_service.get_country_locality_count(country_code).await?;
-        db_count_map.insert(country_code.clone(), db_count);
-    }
-
-    let mut results = Vec::new();
-    let mut all_complete = true;
-
-    for country_code in &countries_to_check {
-        let country_name = country_service
-            .get_country_name(country_code)
-            .unwrap_or(country_code);
-        let db_count = db_count_map.get(country_code).unwrap_or(&0);
-        let file_count = file_count_map.get(country_code).unwrap_or(&0);
-        let is_complete = db_count == file_count;
-
-        if !is_complete {
-            all_complete = false;
-        }
-
-        results.push((
-            country_code,
-            country_name,
-            *db_count,
-            *file_count,
-            is_complete,
-        ));
-    }
-
-    if all_complete {
+    if checks.iter().all(|check| check.is_complete()) {
         info!("✓ All localities have been extracted!");
         return Ok(());
     }
 
-    info!("Country Code | Country Name                  | DB Count | File Count | Status");
-    info!("-------------|-------------------------------|----------|------------|--------");
+    info!("Country Code | Country Name                  | DB Count | File Count | Missing | Invalid | Orphaned | Country Archive | Status");
+    info!("-------------|-------------------------------|----------|------------|---------|---------|----------|-----------------|--------");
 
-    for (country_code, country_name, db_count, file_count, is_complete) in &results {
-        let status = if *is_complete {
+    for check in &checks {
+        let country_name = country_service
+            .get_country_name(&check.country_code)
+            .unwrap_or(&check.country_code);
+        let status = if check.is_complete() {
             "✓ Complete"
         } else {
             "✗ Incomplete"
@@ -179,17 +242,50 @@ pub async fn ensure_all_localities_present(
         } else {
             country_name.to_string()
         };
+        let country_archive = if check.has_country_archive {
+            "✓"
+        } else {
+            "✗"
+        };
         info!(
-            "{:12} | {:29} | {:8} | {:10} | {}",
-            country_code, truncated_name, db_count, file_count, status
+            "{:12} | {:29} | {:8} | {:10} | {:7} | {:7} | {:8} | {:15} | {}",
+            check.country_code,
+            truncated_name,
+            check.expected,
+            check.present,
+            check.missing.len(),
+            check.invalid.len(),
+            check.orphaned.len(),
+            country_archive,
+            status
         );
     }
 
+    for check in &checks {
+        if !check.invalid.is_empty() {
+            warn!(
+                "Invalid archives for {}: {:?}",
+                check.country_code, check.invalid
+            );
+        }
+        if !check.orphaned.is_empty() {
+            info!(
+                "Orphaned archives for {} (remove with the prune command): {:?}",
+                check.country_code, check.orphaned
+            );
+        }
+    }
+
     warn!("✗ Some localities are missing. Extraction is incomplete.");
 
+    if !check_disk_space(config, budget_service).await {
+        info!("Extraction skipped.");
+        return Ok(());
+    }
+
     if args.should_extract_localities() {
         info!("Auto-extracting missing localities...");
-        extract_missing_localities(extraction_service, results.clone()).await?;
+        extract_missing_localities(extraction_service, &checks).await?;
         return Ok(());
     } else if args.is_interactive_mode() {
         // Interactive mode - prompt the user
@@ -200,7 +296,7 @@ pub async fn ensure_all_localities_present(
         io::stdin().read_line(&mut input)?;
 
         if input.trim().to_lowercase() == "y" {
-            extract_missing_localities(extraction_service, results.clone()).await?;
+            extract_missing_localities(extraction_service, &checks).await?;
             return Ok(());
         }
     }
@@ -1,21 +1,25 @@
 use crate::{
-    api::{countries, localities, pmtiles},
-    config::Config,
+    api::{admin, countries, health, localities, onion, pmtiles, regions, signing},
+    config::{BindAddress, Config, OnionRoute},
     initialization::{
         ensure_all_localities_present, ensure_database_is_present, ensure_tools_are_present,
+        prune_orphaned_archives,
     },
-    services::tor::TorServiceManager,
-    services::{country::CountryService, database::DatabaseService, extraction::ExtractionService},
-};
-use axum::{
-    routing::{get, Router},
-    Json,
+    models::{extraction::PruneMode, tor::TorStatusHandle},
+    services::tor::{self, TorServiceManager},
+    services::{
+        budget::BudgetService, bundle::BundleService, country::CountryService,
+        database::DatabaseService, extraction::ExtractionService, signing::SigningService,
+        storage::StorageService,
+    },
+    utils::{listener, tls::TlsState},
 };
+use axum::routing::{get, post, Router};
 use clap::Parser;
-use reqwest::StatusCode;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
-use tracing::error;
+use tracing::{error, info};
 
 mod api;
 mod cli;
@@ -31,6 +35,13 @@ pub struct AppState {
     pub db_service: Arc<DatabaseService>,
     pub extraction_service: Arc<ExtractionService>,
     pub country_service: Arc<CountryService>,
+    pub storage_service: Arc<StorageService>,
+    pub budget_service: Arc<BudgetService>,
+    pub signing_service: Arc<SigningService>,
+    /// Public onion URL, set once the onion service has launched; read on every
+    /// clearnet response for the `Onion-Location` header
+    pub onion_base_url: Arc<std::sync::OnceLock<String>>,
+    pub tor_status: Arc<TorStatusHandle>,
 }
 
 #[tokio::main]
@@ -47,9 +58,107 @@ async fn main() {
         }
     };
 
-    if let Err(e) =
-        ensure_tools_are_present(&[&config.pmtiles_cmd, &config.bzip2_cmd, &config.find_cmd]).await
-    {
+    if let Some(cli::Command::OnionKey { admin, action }) = &args.command {
+        let nickname = if *admin {
+            tor::ADMIN_ONION_SERVICE_NICKNAME
+        } else {
+            tor::ONION_SERVICE_NICKNAME
+        };
+        let (result, done) = match action {
+            cli::OnionKeyAction::Export { output } => (
+                tor::export_onion_key(&config.tor_state_dir(), nickname, output),
+                "exported",
+            ),
+            cli::OnionKeyAction::Import { key, force } => (
+                tor::import_onion_key(&config.tor_state_dir(), nickname, key, *force),
+                "imported",
+            ),
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Onion service key {}", done);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Failed to manage the onion service key: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(cli::Command::ClientAuth { admin, action }) = &args.command {
+        let client_auth_dir = if *admin {
+            config.onion_admin_client_auth_dir()
+        } else {
+            config.onion_client_auth_dir()
+        };
+
+        let result = match action {
+            cli::ClientAuthAction::Add { nickname, key } => {
+                tor::add_authorized_client(&client_auth_dir, nickname, key)
+                    .map(|()| info!("Authorized client {}", nickname))
+            }
+            cli::ClientAuthAction::List => {
+                tor::list_authorized_clients(&client_auth_dir).map(|clients| {
+                    info!("{} authorized clients", clients.len());
+                    for (nickname, key) in clients {
+                        info!("{:20} {}", nickname, key);
+                    }
+                })
+            }
+            cli::ClientAuthAction::Revoke { nickname } => {
+                tor::revoke_authorized_client(&client_auth_dir, nickname)
+                    .map(|()| info!("Revoked client {}", nickname))
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Failed to manage authorized clients: {}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    let storage_service = Arc::new(StorageService::new(
+        config.tiles_dir(),
+        config.localities_dir(),
+        config.quarantine_dir(),
+    ));
+    let bundle_service = BundleService::new(config.clone(), storage_service.clone());
+
+    // Importing happens before anything else, as it seeds the database
+    if let Some(cli::Command::Import { bundle }) = &args.command {
+        if let Err(e) = ensure_tools_are_present(&[config.tar_cmd.as_str()]).await {
+            error!("Failed to ensure tools are present: {}", e);
+            std::process::exit(1);
+        }
+
+        match bundle_service.import(bundle).await {
+            Ok(countries) => {
+                info!("Imported bundle for countries: {}", countries.join(", "));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Failed to import bundle: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut required_tools = vec![
+        config.pmtiles_cmd.as_str(),
+        config.bzip2_cmd.as_str(),
+        config.find_cmd.as_str(),
+    ];
+    if config.filters_layers() {
+        required_tools.push(config.tile_join_cmd.as_str());
+    }
+    if let Some(cli::Command::Export { .. }) = &args.command {
+        required_tools.push(config.tar_cmd.as_str());
+    }
+
+    if let Err(e) = ensure_tools_are_present(&required_tools).await {
         error!("Failed to ensure tools are present: {}", e);
         std::process::exit(1);
     }
@@ -82,13 +191,87 @@ async fn main() {
         }
     };
 
-    let extraction_service = Arc::new(ExtractionService::new(config.clone(), db_service.clone()));
+    let budget_service = match BudgetService::new(&config, storage_service.clone()).await {
+        Ok(service) => Arc::new(service),
+        Err(e) => {
+            error!("Failed to initialize budget service: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let signing_service = match SigningService::new(&config.signing_key_path()) {
+        Ok(service) => Arc::new(service),
+        Err(e) => {
+            error!("Failed to initialize signing service: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let extraction_service = Arc::new(ExtractionService::new(
+        config.clone(),
+        db_service.clone(),
+        storage_service.clone(),
+        budget_service.clone(),
+    ));
+
+    extraction_service.preload_active_planet().await;
+
+    if let Some(cli::Command::Prune {
+        dry_run,
+        quarantine,
+    }) = args.command
+    {
+        let mode = if dry_run {
+            PruneMode::DryRun
+        } else if quarantine {
+            PruneMode::Quarantine
+        } else {
+            PruneMode::Delete
+        };
+
+        match prune_orphaned_archives(&extraction_service, mode).await {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                error!("Failed to prune orphaned archives: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(cli::Command::Export { countries, output }) = &args.command {
+        let country_codes = country_service.get_countries_to_process(countries);
+        if country_codes.is_empty() {
+            error!("No known countries to export");
+            std::process::exit(1);
+        }
+
+        match bundle_service
+            .export(&db_service, &extraction_service, &country_codes, output)
+            .await
+        {
+            Ok(()) => {
+                info!("Exported bundle to {}", output.display());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Failed to export bundle: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.prune {
+        if let Err(e) = prune_orphaned_archives(&extraction_service, PruneMode::Quarantine).await {
+            error!("Failed to prune orphaned archives: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     if let Err(e) = ensure_all_localities_present(
         &extraction_service,
         &country_service,
+        &budget_service,
         &config,
-        &db_service,
         &args,
     )
     .await
@@ -97,6 +280,14 @@ async fn main() {
         std::process::exit(1);
     }
 
+    if args.update {
+        tracing::info!("Refreshing archives produced by an older planet build...");
+        extraction_service
+            .start_refresh(country_service.get_countries_to_process(&config.target_countries));
+    }
+
+    extraction_service.start_region_sweep();
+
     tracing::info!("Initialization complete, starting services...");
 
     let app_state = AppState {
@@ -104,10 +295,23 @@ async fn main() {
         db_service: db_service.clone(),
         extraction_service: extraction_service.clone(),
         country_service: country_service.clone(),
+        storage_service: storage_service.clone(),
+        budget_service: budget_service.clone(),
+        signing_service: signing_service.clone(),
+        onion_base_url: Arc::new(std::sync::OnceLock::new()),
+        tor_status: Arc::new(TorStatusHandle::new(config.serve_onion)),
     };
 
     let app = Router::new()
         .route("/countries", get(countries::search_countries))
+        .route(
+            "/countries/{country_code}/pmtiles",
+            get(pmtiles::serve_country_pmtiles),
+        )
+        .route(
+            "/countries/{country_code}/manifest.json",
+            get(countries::get_country_manifest),
+        )
         .route(
             "/countries/{country_code}/localities",
             get(localities::search_localities),
@@ -117,55 +321,155 @@ async fn main() {
             get(pmtiles::serve_pmtiles),
         )
         .route(
-            "/health",
-            get({
-                (
-                    StatusCode::OK,
-                    Json(serde_json::json!({ "status": "healthy" })),
-                )
-            }),
+            "/countries/{country_code}/localities/{id}/pmtiles/delta",
+            get(pmtiles::serve_pmtiles_delta),
+        )
+        .route(
+            "/countries/{country_code}/localities/{id}/pmtiles/status",
+            get(pmtiles::get_pmtiles_status),
+        )
+        .route("/regions", post(regions::create_region))
+        .route("/regions/{job_id}", get(regions::get_region))
+        .route(
+            "/regions/{job_id}/pmtiles",
+            get(regions::serve_region_pmtiles),
         )
+        .route("/health", get(health::health_check))
+        .route("/pubkey", get(signing::get_public_key))
+        .route("/onion", get(onion::get_onion))
         .layer(CorsLayer::permissive())
         .with_state(app_state.clone());
 
+    // Admin endpoints are served on localhost, and through the onion service only on an
+    // admin port of ONION_PORTS, which requires restricted discovery
+    let admin_app = Router::new()
+        .route(
+            "/admin/extractions",
+            get(admin::list_extractions).post(admin::start_extraction),
+        )
+        .route(
+            "/admin/extractions/refresh",
+            post(admin::refresh_extraction),
+        )
+        .route(
+            "/admin/extractions/{country_code}",
+            get(admin::get_extraction),
+        )
+        .route(
+            "/admin/extractions/{country_code}/pause",
+            post(admin::pause_extraction),
+        )
+        .route(
+            "/admin/extractions/{country_code}/resume",
+            post(admin::resume_extraction),
+        )
+        .route(
+            "/admin/extractions/{country_code}/cancel",
+            post(admin::cancel_extraction),
+        )
+        .route(
+            "/admin/extractions/{country_code}/retry",
+            post(admin::retry_extraction),
+        )
+        .route("/admin/storage", get(admin::get_storage_report))
+        .route("/admin/metrics", get(health::get_metrics))
+        .with_state(app_state.clone());
+
     let shutdown_signal = std::sync::Arc::new(tokio::sync::Notify::new());
 
-    let app_for_tor = app.clone();
-    let app_for_regular = app.clone();
+    let onion_routers = |route: OnionRoute, router: &Router| -> HashMap<u16, Router> {
+        config
+            .onion_ports
+            .iter()
+            .filter(|(_, r)| *r == route)
+            .map(|(port, _)| (*port, router.clone()))
+            .collect()
+    };
+    let public_onion_routers = onion_routers(OnionRoute::Public, &app);
+    let admin_onion_routers = onion_routers(OnionRoute::Admin, &admin_app);
 
-    let shutdown_signal_tor = shutdown_signal.clone();
-    let shutdown_signal_regular = shutdown_signal.clone();
+    if let Some(admin_port) = config.admin_port {
+        let shutdown_signal_admin = shutdown_signal.clone();
+        // The admin API stays on localhost regardless of BIND_ADDRESSES
+        let address = BindAddress::Tcp(std::net::SocketAddr::from(([127, 0, 0, 1], admin_port)));
+        let unix_socket_mode = config.unix_socket_mode;
+        tokio::spawn(async move {
+            run_axum_server(
+                admin_app,
+                "Admin",
+                vec![address],
+                unix_socket_mode,
+                None,
+                shutdown_signal_admin,
+            )
+            .await;
+        });
+    }
 
-    let (onion_address_tx, onion_address_rx) = tokio::sync::oneshot::channel();
+    // Each listener runs independently, so a failing Tor bootstrap does not
+    // take the HTTP listener down with it (and vice versa)
+    let mut handles = Vec::new();
 
-    let app_state_for_tor = app_state.clone();
+    if config.serve_onion {
+        let tor_manager = TorServiceManager::new(
+            public_onion_routers,
+            admin_onion_routers,
+            app_state.clone(),
+            shutdown_signal.clone(),
+        );
 
-    let tor_manager = TorServiceManager::new(app_for_tor, app_state_for_tor, shutdown_signal_tor);
+        handles.push(tokio::spawn(async move {
+            tor_manager.run_with_retry().await;
+        }));
+    } else {
+        info!("Onion service disabled by SERVE_MODES");
+    }
 
-    let tor_handle = tokio::spawn(async move {
-        tor_manager.run_with_retry(onion_address_tx).await;
-    });
+    if config.serve_http {
+        let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                match TlsState::load(cert_path.as_ref(), key_path.as_ref()) {
+                    Ok(tls) => {
+                        tokio::spawn(tls.clone().watch(shutdown_signal.clone()));
+                        Some(tls)
+                    }
+                    Err(e) => {
+                        error!("Failed to load TLS certificate: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => None,
+        };
 
-    let onion_address = match onion_address_rx.await {
-        Ok(address) => address,
-        Err(_) => {
-            error!("Failed to get onion address from Tor hidden service");
-            return;
-        }
-    };
+        // Sent on plain HTTP too, as a reverse proxy in front may terminate TLS
+        let app_for_regular = app.clone().layer(axum::middleware::from_fn_with_state(
+            app_state.onion_base_url.clone(),
+            onion::onion_location_header,
+        ));
+        let shutdown_signal_regular = shutdown_signal.clone();
+        let bind_addresses = config.bind_addresses.clone();
+        let unix_socket_mode = config.unix_socket_mode;
 
-    {
-        let mut config = app_state.config.lock().await;
-        config.onion_address = Some(onion_address.clone());
+        handles.push(tokio::spawn(async move {
+            run_axum_server(
+                app_for_regular,
+                "Axum",
+                bind_addresses,
+                unix_socket_mode,
+                tls,
+                shutdown_signal_regular,
+            )
+            .await;
+        }));
+    } else {
+        info!("HTTP listener disabled by SERVE_MODES");
     }
 
-    let regular_handle = tokio::spawn(async move {
-        run_axum_server(app_for_regular, config.clone(), shutdown_signal_regular).await;
-    });
-
     tokio::select! {
-        _ = tor_handle => {}
-        _ = regular_handle => {}
+        _ = futures::future::join_all(handles) => {
+            error!("All listeners have stopped");
+        }
         _ = tokio::signal::ctrl_c() => {
             tracing::info!("\nShutdown signal received, shutting down...");
             shutdown_signal.notify_waiters();
@@ -177,43 +481,40 @@ async fn main() {
 
 async fn run_axum_server(
     app: Router,
-    config: Arc<Config>,
+    name: &str,
+    addresses: Vec<BindAddress>,
+    unix_socket_mode: u32,
+    tls: Option<TlsState>,
     shutdown_signal: std::sync::Arc<tokio::sync::Notify>,
 ) {
-    tracing::info!("Starting Axum server...");
+    tracing::info!("Starting {} server...", name);
 
-    let address = "127.0.0.1";
-    let port = config.server_port;
-
-    let listener = match tokio::net::TcpListener::bind(&format!("{}:{}", address, port)).await {
-        Ok(listener) => {
-            tracing::info!("TCP listener bound to {}:{}", address, port);
-            listener
-        }
-        Err(e) => {
-            error!(
-                "TCP listener: Failed to bind to {}:{}: {}",
-                address, port, e
-            );
-            return;
+    let mut listeners = Vec::new();
+    for address in &addresses {
+        match listener::bind(address, unix_socket_mode).await {
+            Ok(bound) => listeners.extend(bound),
+            Err(e) => error!("{} server: Failed to bind to {}: {}", name, address, e),
         }
-    };
+    }
 
-    let shutdown = shutdown_signal.clone();
+    if listeners.is_empty() {
+        error!("{} server has no listener to serve on", name);
+        return;
+    }
 
-    match axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            tracing::info!("✓ Axum server successfully started");
-            shutdown.notified().await;
-            tracing::info!("Axum server shutting down...");
+    let handles: Vec<_> = listeners
+        .into_iter()
+        .map(|l| {
+            tokio::spawn(listener::serve(
+                app.clone(),
+                name.to_string(),
+                l,
+                tls.clone(),
+                shutdown_signal.clone(),
+            ))
         })
-        .await
-    {
-        Ok(_) => {
-            tracing::info!("Axum server stopped successfully");
-        }
-        Err(e) => {
-            error!("Axum server error: {}", e);
-        }
-    }
+        .collect();
+
+    tracing::info!("✓ {} server successfully started", name);
+    futures::future::join_all(handles).await;
 }
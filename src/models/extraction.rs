@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ExtractionStatus {
+    Ready,
+    Pending,
+    Failed { error: String },
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Paused,
+    Cancelled,
+    Completed,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobState::Running | JobState::Paused)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionJob {
+    pub country_code: String,
+    pub state: JobState,
+    pub total: usize,
+    pub completed: usize,
+    pub failed: Vec<i64>,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanetSource {
+    pub url: String,
+    pub build: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractionProfile {
+    pub min_zoom: Option<u8>,
+    pub max_zoom: Option<u8>,
+    pub layers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+    pub build: String,
+    pub extracted_at: u64,
+    #[serde(default)]
+    pub profile: ExtractionProfile,
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneMode {
+    DryRun,
+    Delete,
+    Quarantine,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub country_code: String,
+    pub orphans: Vec<i64>,
+    pub bytes: u64,
+}
+
+/// Id-level comparison of the localities of a country in the database with its archives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountryArchiveCheck {
+    pub country_code: String,
+    pub expected: usize,
+    pub present: usize,
+    pub missing: Vec<i64>,
+    pub orphaned: Vec<i64>,
+    pub invalid: Vec<i64>,
+    pub has_country_archive: bool,
+}
+
+impl CountryArchiveCheck {
+    /// Orphaned archives do not make a country incomplete, they are left to pruning.
+    /// Countries without localities have no extent to build a country archive from.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+            && self.invalid.is_empty()
+            && (self.has_country_archive || self.expected == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check() -> CountryArchiveCheck {
+        CountryArchiveCheck {
+            country_code: "FR".to_string(),
+            expected: 2,
+            present: 2,
+            missing: Vec::new(),
+            orphaned: Vec::new(),
+            invalid: Vec::new(),
+            has_country_archive: true,
+        }
+    }
+
+    #[test]
+    fn countries_with_every_archive_are_complete() {
+        assert!(check().is_complete());
+        assert!(CountryArchiveCheck {
+            orphaned: vec![103],
+            present: 3,
+            ..check()
+        }
+        .is_complete());
+        assert!(CountryArchiveCheck {
+            expected: 0,
+            present: 0,
+            has_country_archive: false,
+            ..check()
+        }
+        .is_complete());
+    }
+
+    #[test]
+    fn missing_or_invalid_archives_make_countries_incomplete() {
+        assert!(!CountryArchiveCheck {
+            missing: vec![101],
+            present: 1,
+            ..check()
+        }
+        .is_complete());
+        assert!(!CountryArchiveCheck {
+            invalid: vec![102],
+            ..check()
+        }
+        .is_complete());
+        assert!(!CountryArchiveCheck {
+            has_country_archive: false,
+            ..check()
+        }
+        .is_complete());
+    }
+}
@@ -45,15 +45,18 @@ pub struct LocalityInfo {
     pub max_longitude: f64,
     pub max_latitude: f64,
     pub file_size: u64,
-    pub onion_link: String,
+    pub http_link: Option<String>,
+    pub onion_link: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaginatedLocalitiesResult {
     pub localities: Vec<LocalityInfo>,
     pub pagination: PaginationInfo,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaginationInfo {
     pub page: u32,
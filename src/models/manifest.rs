@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub id: i64,
+    pub name: String,
+    pub bbox: [f64; 4],
+    pub size: u64,
+    pub sha256: String,
+    pub build: Option<String>,
+    pub extracted_at: Option<u64>,
+}
+
+/// The locality archives available for a country, as published at
+/// `/countries/{country_code}/manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountryManifest {
+    pub country_code: String,
+    pub generated_at: u64,
+    pub archives: Vec<ManifestEntry>,
+}
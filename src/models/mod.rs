@@ -1,3 +1,7 @@
 pub mod country;
+pub mod extraction;
 pub mod locality;
+pub mod manifest;
 pub mod response;
+pub mod storage;
+pub mod tor;
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub hash: String,
+    pub length: u64,
+}
+
+/// A virtual archive: the concatenation of its blobs, in order, is the original archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveLayout {
+    pub segments: Vec<BlobRef>,
+}
+
+impl ArchiveLayout {
+    pub fn length(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.length).sum()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageReport {
+    pub archives: usize,
+    pub blobs: usize,
+    pub logical_bytes: u64,
+    pub stored_bytes: u64,
+    pub saved_bytes: u64,
+}
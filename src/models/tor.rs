@@ -0,0 +1,205 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use tor_hsservice::status::State;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnionState {
+    /// The onion service is disabled by `SERVE_MODES`
+    Disabled,
+    /// The Tor client is bootstrapping
+    Bootstrapping,
+    /// The service is launched and publishing its descriptors
+    Launching,
+    Reachable,
+    /// Reachable, but with some descriptors or introduction points failing
+    Degraded,
+    Unreachable,
+    /// The last attempt failed and another one is scheduled
+    Retrying,
+    /// The retry policy gave up
+    Failed,
+    Stopped,
+}
+
+impl OnionState {
+    pub const ALL: [OnionState; 9] = [
+        OnionState::Disabled,
+        OnionState::Bootstrapping,
+        OnionState::Launching,
+        OnionState::Reachable,
+        OnionState::Degraded,
+        OnionState::Unreachable,
+        OnionState::Retrying,
+        OnionState::Failed,
+        OnionState::Stopped,
+    ];
+
+    /// Maps the state reported by Arti's onion service status events.
+    pub fn from_service_state(service_state: State) -> Self {
+        match service_state {
+            State::Running => OnionState::Reachable,
+            State::DegradedReachable => OnionState::Degraded,
+            State::Bootstrapping => OnionState::Launching,
+            State::Shutdown => OnionState::Stopped,
+            State::DegradedUnreachable | State::Recovering | State::Broken => {
+                OnionState::Unreachable
+            }
+            // States added by later Arti versions are not known to be reachable
+            _ => OnionState::Unreachable,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnionState::Disabled => "disabled",
+            OnionState::Bootstrapping => "bootstrapping",
+            OnionState::Launching => "launching",
+            OnionState::Reachable => "reachable",
+            OnionState::Degraded => "degraded",
+            OnionState::Unreachable => "unreachable",
+            OnionState::Retrying => "retrying",
+            OnionState::Failed => "failed",
+            OnionState::Stopped => "stopped",
+        }
+    }
+}
+
+/// Streams refused by the onion service, by reason.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RejectedStreams {
+    /// Streams to a port missing from `ONION_PORTS`
+    pub unmapped_port: u64,
+    /// Streams over the per-circuit concurrent stream limit
+    pub stream_limit: u64,
+}
+
+/// The onion service status reported by `/health`, `/onion` and the metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorStatus {
+    pub state: OnionState,
+    /// The raw state of Arti's onion service, e.g. `Running` or `Recovering`
+    pub service_state: Option<String>,
+    /// Tor client bootstrap progress, from 0 to 100
+    pub bootstrap_progress: u8,
+    pub bootstrap_blocked: Option<String>,
+    /// The current attempt, starting at 1
+    pub attempt: u32,
+    /// `None` when retrying forever
+    pub max_attempts: Option<u32>,
+    pub last_error: Option<String>,
+    pub next_retry_at: Option<u64>,
+    pub state_changed_at: u64,
+    pub reachable_since: Option<u64>,
+    pub restarts: u64,
+    pub reachability_losses: u64,
+    pub rejected_streams: RejectedStreams,
+}
+
+impl TorStatus {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            state: if enabled {
+                OnionState::Bootstrapping
+            } else {
+                OnionState::Disabled
+            },
+            service_state: None,
+            bootstrap_progress: 0,
+            bootstrap_blocked: None,
+            attempt: 0,
+            max_attempts: None,
+            last_error: None,
+            next_retry_at: None,
+            state_changed_at: unix_timestamp(),
+            reachable_since: None,
+            restarts: 0,
+            reachability_losses: 0,
+            rejected_streams: RejectedStreams::default(),
+        }
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        matches!(self.state, OnionState::Reachable | OnionState::Degraded)
+    }
+
+    pub fn retrying(&mut self, error: String, delay: std::time::Duration) {
+        self.last_error = Some(error);
+        self.next_retry_at = Some(unix_timestamp() + delay.as_secs());
+        self.set_state(OnionState::Retrying);
+    }
+
+    pub fn failed(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.next_retry_at = None;
+        self.set_state(OnionState::Failed);
+    }
+
+    pub fn set_state(&mut self, state: OnionState) {
+        if state == self.state {
+            return;
+        }
+
+        let was_reachable = self.is_reachable();
+        self.state = state;
+        self.state_changed_at = unix_timestamp();
+
+        match (was_reachable, self.is_reachable()) {
+            (false, true) => self.reachable_since = Some(self.state_changed_at),
+            (true, false) => {
+                self.reachable_since = None;
+                self.reachability_losses += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The onion service status shared by the Tor service manager with `/health`, `/onion`
+/// and the metrics. Rejected streams are counted on every refused stream, so they are
+/// plain atomics rather than part of the locked status.
+pub struct TorStatusHandle {
+    status: RwLock<TorStatus>,
+    unmapped_port: AtomicU64,
+    stream_limit: AtomicU64,
+}
+
+impl TorStatusHandle {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            status: RwLock::new(TorStatus::new(enabled)),
+            unmapped_port: AtomicU64::new(0),
+            stream_limit: AtomicU64::new(0),
+        }
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut TorStatus)) {
+        update(&mut self.status.write().unwrap());
+    }
+
+    /// A copy of the current status, with the rejected stream counters.
+    pub fn snapshot(&self) -> TorStatus {
+        let mut status = self.status.read().unwrap().clone();
+        status.rejected_streams = RejectedStreams {
+            unmapped_port: self.unmapped_port.load(Ordering::Relaxed),
+            stream_limit: self.stream_limit.load(Ordering::Relaxed),
+        };
+        status
+    }
+
+    pub fn reject_unmapped_port(&self) {
+        self.unmapped_port.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reject_over_stream_limit(&self) {
+        self.stream_limit.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,367 @@
+use crate::config::Config;
+use crate::models::storage::ArchiveLayout;
+use crate::services::storage::{StorageError, StorageService};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum BudgetError {
+    #[error("Disk budget exceeded: {0}")]
+    BudgetExceeded(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Tokio join error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CandidateKind {
+    /// A plain locality, country or region archive
+    Archive,
+    /// A locality archive stored as a layout in the tile store
+    Layout,
+    /// The previous versions of a locality archive and the deltas built from them
+    Versions,
+}
+
+/// Something in the assets directory that can be evicted.
+struct Candidate {
+    /// The `{id}.pmtiles` path, also for archives stored as a layout, or the versions directory
+    path: PathBuf,
+    /// The archive size, the logical size for a layout, or the size of a versions directory
+    size: u64,
+    kind: CandidateKind,
+    /// The country whose manifest lists the archive, for locality archives
+    country_code: Option<String>,
+    /// The last access or modification time on disk, ordering archives not requested since startup
+    touched_at: u64,
+}
+
+/// Keeps the assets directory within `MAX_ASSETS_BYTES`, evicting the least recently
+/// requested archives when eviction is enabled.
+pub struct BudgetService {
+    storage_service: Arc<StorageService>,
+    assets_dir: PathBuf,
+    localities_dir: PathBuf,
+    countries_dir: PathBuf,
+    regions_dir: PathBuf,
+    max_bytes: Option<u64>,
+    evict: bool,
+    usage: AtomicU64,
+    last_access: Mutex<HashMap<PathBuf, u64>>,
+    eviction_lock: Mutex<()>,
+}
+
+impl BudgetService {
+    pub async fn new(
+        config: &Config,
+        storage_service: Arc<StorageService>,
+    ) -> Result<Self, BudgetError> {
+        let evict = config.evict_archives && config.max_assets_bytes.is_some();
+        if evict && !config.lazy_extraction {
+            warn!("Archive eviction requires LAZY_EXTRACTION, eviction is disabled");
+        }
+
+        let service = Self {
+            storage_service,
+            assets_dir: PathBuf::from(&config.assets_dir),
+            localities_dir: config.localities_dir(),
+            countries_dir: config.countries_dir(),
+            regions_dir: config.regions_dir(),
+            max_bytes: config.max_assets_bytes,
+            evict: evict && config.lazy_extraction,
+            usage: AtomicU64::new(0),
+            last_access: Mutex::new(HashMap::new()),
+            eviction_lock: Mutex::new(()),
+        };
+
+        service.refresh_usage().await?;
+
+        Ok(service)
+    }
+
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    pub fn evicts(&self) -> bool {
+        self.evict
+    }
+
+    /// Recomputes the usage from disk, correcting the estimate kept between extractions.
+    pub async fn refresh_usage(&self) -> Result<(), BudgetError> {
+        let assets_dir = self.assets_dir.clone();
+        let usage = tokio::task::spawn_blocking(move || dir_size(&assets_dir)).await??;
+        self.usage.store(usage, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Accounts for an archive of `current` bytes replacing one of `previous` bytes.
+    pub fn record_written(&self, previous: u64, current: u64) {
+        let _ = self
+            .usage
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                Some(usage.saturating_sub(previous) + current)
+            });
+    }
+
+    pub async fn record_access(&self, archive_path: &Path) {
+        if self.evict {
+            self.last_access
+                .lock()
+                .await
+                .insert(archive_path.to_path_buf(), unix_timestamp());
+        }
+    }
+
+    /// Checked before each extraction. When over budget, evicts the least recently
+    /// requested archives if enabled, or fails otherwise. Returns the countries whose
+    /// locality archives were evicted, so their manifests can be refreshed.
+    pub async fn ensure_capacity(&self) -> Result<Vec<String>, BudgetError> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(Vec::new()),
+        };
+
+        if self.usage() < max_bytes {
+            return Ok(Vec::new());
+        }
+
+        if !self.evict {
+            return Err(BudgetError::BudgetExceeded(format!(
+                "{} of {} bytes used",
+                self.usage(),
+                max_bytes
+            )));
+        }
+
+        let _guard = self.eviction_lock.lock().await;
+
+        // Another extraction may have evicted archives while waiting for the lock
+        if self.usage() < max_bytes {
+            return Ok(Vec::new());
+        }
+
+        let localities_dir = self.localities_dir.clone();
+        let archive_dirs = [self.countries_dir.clone(), self.regions_dir.clone()];
+        let mut candidates = tokio::task::spawn_blocking(move || {
+            let mut candidates = locality_archives(&localities_dir)?;
+            for dir in &archive_dirs {
+                candidates.extend(plain_archives(dir)?);
+            }
+            Ok::<_, std::io::Error>(candidates)
+        })
+        .await??;
+
+        let last_access = self.last_access.lock().await.clone();
+        candidates.sort_by_key(|candidate| {
+            last_access
+                .get(&candidate.path)
+                .copied()
+                .unwrap_or(candidate.touched_at)
+        });
+        let mut candidates = VecDeque::from(candidates);
+
+        // Free a tenth of the budget so evictions do not run before every extraction
+        let target = max_bytes - max_bytes / 10;
+        let mut evicted_countries = BTreeSet::new();
+
+        while self.usage() >= target && !candidates.is_empty() {
+            let mut evicted_layouts = false;
+
+            while let Some(candidate) = candidates.pop_front() {
+                info!("Evicting: {}", candidate.path.display());
+                match candidate.kind {
+                    CandidateKind::Archive => tokio::fs::remove_file(&candidate.path).await?,
+                    CandidateKind::Layout => {
+                        tokio::fs::remove_file(StorageService::layout_path(&candidate.path))
+                            .await?;
+                        evicted_layouts = true;
+                    }
+                    // Kept versions may be layouts too
+                    CandidateKind::Versions => {
+                        tokio::fs::remove_dir_all(&candidate.path).await?;
+                        evicted_layouts = true;
+                    }
+                }
+                if candidate.kind != CandidateKind::Versions {
+                    let _ =
+                        tokio::fs::remove_file(candidate.path.with_extension("build.json")).await;
+                }
+                if let Some(country_code) = candidate.country_code {
+                    evicted_countries.insert(country_code);
+                }
+
+                // Blobs shared with other archives stay, so a layout frees at most its logical size
+                self.record_written(candidate.size, 0);
+                self.last_access.lock().await.remove(&candidate.path);
+
+                if self.usage() < target {
+                    break;
+                }
+            }
+
+            // Release the blobs of evicted layouts, then measure what was actually freed
+            if evicted_layouts {
+                self.storage_service.collect_garbage().await?;
+                self.refresh_usage().await?;
+            }
+        }
+
+        if self.usage() >= max_bytes {
+            return Err(BudgetError::BudgetExceeded(format!(
+                "{} of {} bytes used after evicting every archive",
+                self.usage(),
+                max_bytes
+            )));
+        }
+
+        Ok(evicted_countries.into_iter().collect())
+    }
+}
+
+fn dir_size(dir: &Path) -> Result<u64, std::io::Error> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// The locality archives that can be evicted, plain or stored as a layout, and the
+/// previous versions kept for deltas.
+fn locality_archives(localities_dir: &Path) -> Result<Vec<Candidate>, std::io::Error> {
+    let mut archives = Vec::new();
+
+    if !localities_dir.exists() {
+        return Ok(archives);
+    }
+
+    for country_entry in std::fs::read_dir(localities_dir)? {
+        let country_dir = country_entry?.path();
+        if !country_dir.is_dir() {
+            continue;
+        }
+
+        let country_code = country_dir
+            .file_name()
+            .and_then(|s| s.to_str())
+            .map(str::to_string);
+
+        for entry in std::fs::read_dir(&country_dir)? {
+            let path = entry?.path();
+            let file_name = match path.file_name().and_then(|s| s.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let metadata = std::fs::metadata(&path)?;
+            let touched_at = last_touched(&metadata);
+
+            if file_name.ends_with(".pmtiles") && metadata.is_file() {
+                archives.push(Candidate {
+                    size: metadata.len(),
+                    path,
+                    kind: CandidateKind::Archive,
+                    country_code: country_code.clone(),
+                    touched_at,
+                });
+            } else if let Some(id) = file_name.strip_suffix(".layout.json") {
+                let layout: Option<ArchiveLayout> = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|content| serde_json::from_str(&content).ok());
+                if let Some(layout) = layout {
+                    archives.push(Candidate {
+                        path: country_dir.join(format!("{}.pmtiles", id)),
+                        size: layout.length(),
+                        kind: CandidateKind::Layout,
+                        country_code: country_code.clone(),
+                        touched_at,
+                    });
+                }
+            } else if file_name == ".versions" && metadata.is_dir() {
+                for entry in std::fs::read_dir(&path)? {
+                    let path = entry?.path();
+                    let metadata = std::fs::metadata(&path)?;
+                    if metadata.is_dir() {
+                        archives.push(Candidate {
+                            size: dir_size(&path)?,
+                            touched_at: last_touched(&metadata),
+                            path,
+                            kind: CandidateKind::Versions,
+                            country_code: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(archives)
+}
+
+/// The plain archives directly inside a directory, such as country and region archives.
+fn plain_archives(dir: &Path) -> Result<Vec<Candidate>, std::io::Error> {
+    let mut archives = Vec::new();
+
+    if !dir.exists() {
+        return Ok(archives);
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = std::fs::metadata(&path)?;
+
+        if metadata.is_file() && path.extension().is_some_and(|ext| ext == "pmtiles") {
+            archives.push(Candidate {
+                size: metadata.len(),
+                touched_at: last_touched(&metadata),
+                path,
+                kind: CandidateKind::Archive,
+                country_code: None,
+            });
+        }
+    }
+
+    Ok(archives)
+}
+
+/// The latest of the access and modification times of a file.
+fn last_touched(metadata: &std::fs::Metadata) -> u64 {
+    [metadata.accessed(), metadata.modified()]
+        .into_iter()
+        .filter_map(|time| time.ok()?.duration_since(UNIX_EPOCH).ok())
+        .max()
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,423 @@
+use crate::config::Config;
+use crate::services::country::CountryService;
+use crate::services::database::{DatabaseError, DatabaseService};
+use crate::services::extraction::{ExtractionError, ExtractionService};
+use crate::services::storage::{StorageError, StorageService};
+use crate::utils::cmd::{run_command, CmdError};
+use crate::utils::file::{ensure_dir_exists, FileError};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{info, warn};
+
+const BUNDLE_FORMAT: &str = "localitysrv-bundle-1";
+const BUNDLE_DATABASE: &str = "whosonfirst.db";
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Cmd error: {0}")]
+    CmdError(#[from] CmdError),
+    #[error("File error: {0}")]
+    FileError(#[from] FileError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] DatabaseError),
+    #[error("Rusqlite error: {0}")]
+    RusqliteError(#[from] rusqlite::Error),
+    #[error("Extraction error: {0}")]
+    ExtractionError(#[from] ExtractionError),
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Tokio join error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleInfo {
+    format: String,
+    created_at: u64,
+    countries: Vec<String>,
+}
+
+/// Packages countries into tar/zstd bundles for offline distribution, and seeds the
+/// assets directory from them.
+pub struct BundleService {
+    config: Arc<Config>,
+    storage_service: Arc<StorageService>,
+}
+
+impl BundleService {
+    pub fn new(config: Arc<Config>, storage_service: Arc<StorageService>) -> Self {
+        Self {
+            config,
+            storage_service,
+        }
+    }
+
+    /// The bundle is staged inside the assets directory, so imports can move files in place.
+    fn staging_dir(&self) -> PathBuf {
+        PathBuf::from(&self.config.assets_dir).join(".bundle")
+    }
+
+    /// Writes a bundle of the locality archives, manifests and country archives of the given
+    /// countries, their WhosOnFirst locality rows and the country codes.
+    pub async fn export(
+        &self,
+        db_service: &DatabaseService,
+        extraction_service: &ExtractionService,
+        country_codes: &[String],
+        output_path: &Path,
+    ) -> Result<(), BundleError> {
+        let staging_dir = self.staging_dir();
+        if staging_dir.exists() {
+            tokio::fs::remove_dir_all(&staging_dir).await?;
+        }
+
+        let result = self
+            .stage_export(db_service, extraction_service, country_codes, &staging_dir)
+            .await;
+
+        let result = match result {
+            Ok(()) => {
+                // tar changes to the staging directory, so paths are made absolute
+                let output_path = std::env::current_dir()?.join(output_path);
+                let output_path = output_path.to_string_lossy().to_string();
+                let staging_path = staging_dir.to_string_lossy().to_string();
+
+                info!("Compressing bundle to {}", output_path);
+                run_command(
+                    &self.config.tar_cmd,
+                    &["--zstd", "-cf", &output_path, "-C", &staging_path, "."],
+                    None,
+                )
+                .await
+                .map(|_| ())
+                .map_err(BundleError::from)
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+        result
+    }
+
+    async fn stage_export(
+        &self,
+        db_service: &DatabaseService,
+        extraction_service: &ExtractionService,
+        country_codes: &[String],
+        staging_dir: &Path,
+    ) -> Result<(), BundleError> {
+        let staging_countries_dir = staging_dir.join("countries");
+        ensure_dir_exists(&staging_countries_dir)?;
+
+        for country_code in country_codes {
+            let manifest = extraction_service.update_manifest(country_code).await?;
+            info!(
+                "Bundling {} locality archives for country: {}",
+                manifest.archives.len(),
+                country_code
+            );
+
+            let country_dir = self.config.localities_dir().join(country_code);
+            let staging_country_dir = staging_dir.join("localities").join(country_code);
+            ensure_dir_exists(&staging_country_dir)?;
+
+            for entry in &manifest.archives {
+                let file_name = format!("{}.pmtiles", entry.id);
+                let archive_path = country_dir.join(&file_name);
+
+                // Archives in the tile store are bundled as plain files
+                if archive_path.exists() {
+                    link_or_copy(&archive_path, &staging_country_dir.join(&file_name)).await?;
+                } else {
+                    let data = self.storage_service.read_archive(&archive_path).await?;
+                    tokio::fs::write(staging_country_dir.join(&file_name), data).await?;
+                }
+
+                let record_name = format!("{}.build.json", entry.id);
+                if country_dir.join(&record_name).exists() {
+                    link_or_copy(
+                        &country_dir.join(&record_name),
+                        &staging_country_dir.join(&record_name),
+                    )
+                    .await?;
+                }
+            }
+
+            let countries_dir = self.config.countries_dir();
+            for file_name in [
+                format!("{}.manifest.json", country_code),
+                format!("{}.pmtiles", country_code),
+                format!("{}.build.json", country_code),
+            ] {
+                if countries_dir.join(&file_name).exists() {
+                    link_or_copy(
+                        &countries_dir.join(&file_name),
+                        &staging_countries_dir.join(&file_name),
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        let row_count = db_service
+            .export_localities(country_codes, &staging_dir.join(BUNDLE_DATABASE))
+            .await?;
+        info!("Bundled {} WhosOnFirst locality rows", row_count);
+
+        let country_codes_path = self.config.country_codes_path();
+        if country_codes_path.exists() {
+            tokio::fs::copy(&country_codes_path, staging_dir.join("country-codes.json")).await?;
+        }
+
+        let bundle_info = BundleInfo {
+            format: BUNDLE_FORMAT.to_string(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            countries: country_codes.to_vec(),
+        };
+        let bundle_json = serde_json::to_string_pretty(&bundle_info)
+            .map_err(|e| BundleError::InvalidBundle(e.to_string()))?;
+        tokio::fs::write(staging_dir.join("bundle.json"), bundle_json).await?;
+
+        Ok(())
+    }
+
+    /// Seeds the assets directory from a bundle. Existing archives are replaced, locality
+    /// rows are added to an existing database and existing country codes are kept.
+    /// Returns the countries of the bundle.
+    pub async fn import(&self, bundle_path: &Path) -> Result<Vec<String>, BundleError> {
+        let staging_dir = self.staging_dir();
+        if staging_dir.exists() {
+            tokio::fs::remove_dir_all(&staging_dir).await?;
+        }
+        ensure_dir_exists(&staging_dir)?;
+
+        let result = self.import_staged(bundle_path, &staging_dir).await;
+
+        let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+        result
+    }
+
+    async fn import_staged(
+        &self,
+        bundle_path: &Path,
+        staging_dir: &Path,
+    ) -> Result<Vec<String>, BundleError> {
+        let bundle_path = std::env::current_dir()?.join(bundle_path);
+        let bundle_path = bundle_path.to_string_lossy().to_string();
+        let staging_path = staging_dir.to_string_lossy().to_string();
+
+        info!("Decompressing bundle {}", bundle_path);
+        run_command(
+            &self.config.tar_cmd,
+            &["--zstd", "-xf", &bundle_path, "-C", &staging_path],
+            None,
+        )
+        .await?;
+
+        let bundle_info: BundleInfo = match std::fs::read_to_string(staging_dir.join("bundle.json"))
+        {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| BundleError::InvalidBundle(e.to_string()))?,
+            Err(_) => {
+                return Err(BundleError::InvalidBundle(
+                    "Missing bundle.json".to_string(),
+                ))
+            }
+        };
+
+        if bundle_info.format != BUNDLE_FORMAT {
+            return Err(BundleError::InvalidBundle(format!(
+                "Unsupported format: {}",
+                bundle_info.format
+            )));
+        }
+
+        let known_country_codes = self.known_country_codes(staging_dir)?;
+        for country_code in &bundle_info.countries {
+            if !is_country_code(country_code) || !known_country_codes.contains_key(country_code) {
+                return Err(BundleError::InvalidBundle(format!(
+                    "Unknown country code: {}",
+                    country_code
+                )));
+            }
+        }
+
+        for country_code in &bundle_info.countries {
+            let country_dir = self.config.localities_dir().join(country_code);
+            let imported = move_files(
+                &staging_dir.join("localities").join(country_code),
+                &country_dir,
+            )
+            .await?;
+            info!(
+                "Imported {} files for country: {}",
+                imported.len(),
+                country_code
+            );
+
+            if self.config.dedup_storage {
+                for path in imported
+                    .iter()
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "pmtiles"))
+                {
+                    if let Err(e) = self.storage_service.ingest_archive(path).await {
+                        warn!(
+                            "Failed to move {} into the tile store, keeping the archive: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        move_files(&staging_dir.join("countries"), &self.config.countries_dir()).await?;
+
+        let country_codes_path = self.config.country_codes_path();
+        if !country_codes_path.exists() && staging_dir.join("country-codes.json").exists() {
+            tokio::fs::rename(staging_dir.join("country-codes.json"), &country_codes_path).await?;
+        }
+
+        let bundle_database = staging_dir.join(BUNDLE_DATABASE);
+        let database_path = self.config.database_path();
+
+        if !database_path.exists() {
+            info!("Seeding the WhosOnFirst database from the bundle");
+            tokio::fs::rename(&bundle_database, &database_path).await?;
+        } else {
+            let row_count = tokio::task::spawn_blocking(move || {
+                let conn = Connection::open(database_path)?;
+                conn.execute(
+                    "ATTACH DATABASE ?1 AS bundle",
+                    [bundle_database.to_string_lossy().to_string()],
+                )?;
+                let row_count = conn.execute(
+                    "INSERT OR IGNORE INTO main.spr SELECT * FROM bundle.spr",
+                    [],
+                )?;
+                conn.execute("DETACH DATABASE bundle", [])?;
+                Ok::<usize, rusqlite::Error>(row_count)
+            })
+            .await??;
+            info!(
+                "Added {} locality rows to the WhosOnFirst database",
+                row_count
+            );
+        }
+
+        Ok(bundle_info.countries)
+    }
+
+    /// The country codes in effect once the bundle is imported: the existing list, else the
+    /// list of the bundle, else the default list.
+    fn known_country_codes(
+        &self,
+        staging_dir: &Path,
+    ) -> Result<HashMap<String, String>, BundleError> {
+        let country_codes_path = self.config.country_codes_path();
+        let bundle_country_codes_path = staging_dir.join("country-codes.json");
+        let path = if country_codes_path.exists() {
+            country_codes_path
+        } else if bundle_country_codes_path.exists() {
+            bundle_country_codes_path
+        } else {
+            return Ok(CountryService::create_default_country_codes());
+        };
+
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| BundleError::InvalidBundle(format!("Invalid {}: {}", path.display(), e)))
+    }
+}
+
+/// Country codes of a bundle are joined into paths, so only two uppercase ASCII letters pass.
+fn is_country_code(country_code: &str) -> bool {
+    country_code.len() == 2 && country_code.bytes().all(|b| b.is_ascii_uppercase())
+}
+
+async fn link_or_copy(source: &Path, destination: &Path) -> Result<(), std::io::Error> {
+    if std::fs::hard_link(source, destination).is_err() {
+        tokio::fs::copy(source, destination).await?;
+    }
+    Ok(())
+}
+
+/// Moves the files of a directory into another one, replacing existing files. Returns the
+/// new paths of the moved files.
+async fn move_files(
+    source_dir: &Path,
+    destination_dir: &Path,
+) -> Result<Vec<PathBuf>, BundleError> {
+    let mut moved = Vec::new();
+
+    if !source_dir.exists() {
+        return Ok(moved);
+    }
+    ensure_dir_exists(destination_dir)?;
+
+    for entry in std::fs::read_dir(source_dir)? {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+
+        let destination = destination_dir.join(entry.file_name());
+        tokio::fs::rename(entry.path(), &destination).await?;
+        moved.push(destination);
+    }
+
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_two_uppercase_letters_are_country_codes() {
+        for country_code in ["FR", "US", "XK"] {
+            assert!(is_country_code(country_code), "{}", country_code);
+        }
+        for country_code in ["", "F", "fr", "FRA", "..", "F/", "/R", "É", "F1"] {
+            assert!(!is_country_code(country_code), "{}", country_code);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_moved_and_replaced() {
+        let dir =
+            std::env::temp_dir().join(format!("localitysrv-move-files-{}", std::process::id()));
+        let source_dir = dir.join("source");
+        let destination_dir = dir.join("destination");
+        std::fs::create_dir_all(source_dir.join("nested")).unwrap();
+        std::fs::create_dir_all(&destination_dir).unwrap();
+        std::fs::write(source_dir.join("101.pmtiles"), b"new").unwrap();
+        std::fs::write(source_dir.join("nested").join("102.pmtiles"), b"nested").unwrap();
+        std::fs::write(destination_dir.join("101.pmtiles"), b"old").unwrap();
+
+        let moved = move_files(&source_dir, &destination_dir).await.unwrap();
+
+        assert_eq!(moved, vec![destination_dir.join("101.pmtiles")]);
+        assert_eq!(std::fs::read(&moved[0]).unwrap(), b"new");
+        assert!(!source_dir.join("101.pmtiles").exists());
+        // Directories are left in place
+        assert!(source_dir.join("nested").join("102.pmtiles").exists());
+        assert!(move_files(&dir.join("missing"), &destination_dir)
+            .await
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
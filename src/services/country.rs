@@ -185,7 +185,7 @@ impl CountryService {
         Ok(count)
     }
 
-    fn create_default_country_codes() -> HashMap<String, String> {
+    pub(crate) fn create_default_country_codes() -> HashMap<String, String> {
         let mut codes = HashMap::new();
 
         codes.insert("US".to_string(), "United States".to_string());
@@ -260,6 +260,40 @@ impl DatabaseService {
         }).await?
     }
 
+    /// Copies the locality rows of the given countries into a new SQLite database.
+    /// Returns the number of copied rows.
+    pub async fn export_localities(
+        &self,
+        country_codes: &[String],
+        output_path: &Path,
+    ) -> Result<usize, DatabaseError> {
+        let conn = self.conn.clone();
+        let country_codes = country_codes.to_vec();
+        let output_path = output_path.to_string_lossy().to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("ATTACH DATABASE ?1 AS bundle", [&output_path])?;
+
+            let result = (|| {
+                conn.execute("CREATE TABLE bundle.spr AS SELECT * FROM main.spr WHERE 0", [])?;
+
+                let mut row_count = 0;
+                for country_code in &country_codes {
+                    row_count += conn.execute(
+                        "INSERT INTO bundle.spr SELECT * FROM main.spr WHERE placetype = 'locality' AND country = ?1",
+                        [country_code],
+                    )?;
+                }
+                Ok::<usize, DatabaseError>(row_count)
+            })();
+
+            conn.execute("DETACH DATABASE bundle", [])?;
+            result
+        })
+        .await?
+    }
+
     pub async fn get_country_localities(
         &self,
         country_code: &str,
@@ -301,6 +335,44 @@ impl DatabaseService {
         }).await?
     }
 
+    pub async fn get_locality(
+        &self,
+        country_code: &str,
+        id: i64,
+    ) -> Result<Option<Locality>, DatabaseError> {
+        let conn = self.conn.clone();
+        let country_code = country_code.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+
+            let conditions = [
+                "placetype = 'locality'",
+                "is_current = 1",
+                "is_deprecated = 0",
+                "min_longitude IS NOT NULL",
+                "min_latitude IS NOT NULL",
+                "max_longitude IS NOT NULL",
+                "max_latitude IS NOT NULL",
+                "country = ?1",
+                "id = ?2",
+            ];
+
+            let where_clause = conditions.join(" AND ");
+            let query_str = format!(
+                "SELECT id, name, country, placetype, latitude, longitude, min_longitude, min_latitude, max_longitude, max_latitude FROM spr WHERE {}",
+                where_clause
+            );
+
+            let mut stmt = conn.prepare(&query_str)?;
+            let mut rows = stmt.query_map(rusqlite::params![country_code, id], |row| {
+                Locality::from_row(row)
+            })?;
+
+            Ok(rows.next().transpose()?)
+        }).await?
+    }
+
     pub async fn get_country_locality_count(
         &self,
         country_code: &str,
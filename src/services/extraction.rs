@@ -1,14 +1,33 @@
 use crate::config::Config;
+use crate::models::extraction::{
+    ArchiveRecord, CountryArchiveCheck, ExtractionJob, ExtractionProfile, ExtractionStatus,
+    JobState, PlanetSource, PruneMode, PruneReport,
+};
 use crate::models::locality::Locality;
+use crate::models::manifest::{CountryManifest, ManifestEntry};
+use crate::services::budget::{BudgetError, BudgetService};
+use crate::services::storage::{StorageError, StorageService};
 use crate::utils::cmd::{run_command, CmdError};
 use crate::utils::file::{ensure_dir_exists, FileError};
 use futures::future::join_all;
-use std::collections::HashMap;
-use std::path::Path;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::sync::Semaphore;
-use tracing::{error, info};
+use tokio::sync::{watch, Mutex, Semaphore};
+use tracing::{error, info, warn};
+
+/// How often expired region archives are removed, at most.
+const REGION_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long on-demand extractions reuse a resolved planet source before checking for a newer build.
+const PLANET_SOURCE_TTL: Duration = Duration::from_secs(60 * 60);
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Error, Debug)]
 pub enum ExtractionError {
@@ -22,42 +41,300 @@ pub enum ExtractionError {
     CommandFailed(String),
     #[error("Database error: {0}")]
     DatabaseError(String),
+    #[error("No extraction job found for country: {0}")]
+    JobNotFound(String),
+    #[error("Invalid job state: {0}")]
+    InvalidJobState(String),
+    #[error("Invalid region: {0}")]
+    InvalidRegion(String),
+    #[error("Too many region extractions in progress ({0}), try again later")]
+    RegionQueueFull(usize),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Cmd error: {0}")]
     CmdError(#[from] CmdError),
     #[error("File error: {0}")]
     FileError(#[from] FileError),
+    #[error("Budget error: {0}")]
+    BudgetError(#[from] BudgetError),
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobControl {
+    Run,
+    Pause,
+    Cancel,
+}
+
+struct JobHandle {
+    job: ExtractionJob,
+    control: watch::Sender<JobControl>,
+}
+
+impl JobHandle {
+    fn new(
+        country_code: &str,
+        total: usize,
+        completed: usize,
+    ) -> (Self, watch::Receiver<JobControl>) {
+        let (control, control_rx) = watch::channel(JobControl::Run);
+        let handle = Self {
+            job: ExtractionJob {
+                country_code: country_code.to_string(),
+                state: JobState::Running,
+                total,
+                completed,
+                failed: Vec::new(),
+                started_at: unix_timestamp(),
+                finished_at: None,
+            },
+            control,
+        };
+
+        (handle, control_rx)
+    }
+
+    /// Moves the job from the `expected` state to `state`, signalling its extraction tasks.
+    fn transition(
+        &mut self,
+        expected: JobState,
+        control: JobControl,
+        state: JobState,
+    ) -> Result<ExtractionJob, ExtractionError> {
+        if self.job.state != expected {
+            return Err(ExtractionError::InvalidJobState(format!(
+                "Job for {} is {:?}",
+                self.job.country_code, self.job.state
+            )));
+        }
+
+        self.control.send_replace(control);
+        self.job.state = state;
+        Ok(self.job.clone())
+    }
+
+    /// Cancels an active job. Its state changes once its extractions in flight have finished.
+    fn cancel(&mut self) -> Result<ExtractionJob, ExtractionError> {
+        if !self.job.state.is_active() {
+            return Err(ExtractionError::InvalidJobState(format!(
+                "Job for {} is not running",
+                self.job.country_code
+            )));
+        }
+
+        self.control.send_replace(JobControl::Cancel);
+        Ok(self.job.clone())
+    }
+
+    fn record_progress(&mut self, id: i64, success: bool) {
+        if success {
+            self.job.completed += 1;
+            info!(
+                "Progress: {}/{} localities extracted for {}",
+                self.job.completed, self.job.total, self.job.country_code
+            );
+        } else {
+            self.job.failed.push(id);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.job.state = if *self.control.borrow() == JobControl::Cancel {
+            JobState::Cancelled
+        } else if !self.job.failed.is_empty() {
+            JobState::Failed
+        } else {
+            JobState::Completed
+        };
+        self.job.finished_at = Some(unix_timestamp());
+    }
 }
 
 #[derive(Clone)]
 pub struct ExtractionService {
     config: Arc<Config>,
     db_service: Arc<super::database::DatabaseService>,
+    storage_service: Arc<StorageService>,
+    budget_service: Arc<BudgetService>,
+    planet_source: Arc<Mutex<Option<(PlanetSource, Instant)>>>,
+    active_planet: Arc<Mutex<Option<PlanetSource>>>,
+    lazy_jobs: Arc<Mutex<HashMap<(String, i64), ExtractionStatus>>>,
+    lazy_semaphore: Arc<Semaphore>,
+    jobs: Arc<Mutex<HashMap<String, JobHandle>>>,
+    /// Pending and failed region jobs, with the time of their last status change
+    regions: Arc<Mutex<HashMap<String, (ExtractionStatus, u64)>>>,
+    region_semaphore: Arc<Semaphore>,
+    /// Manifests of different countries are generated concurrently
+    manifest_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    delta_lock: Arc<Mutex<()>>,
 }
 
 impl ExtractionService {
-    pub fn new(config: Arc<Config>, db_service: Arc<super::database::DatabaseService>) -> Self {
-        Self { config, db_service }
+    pub fn new(
+        config: Arc<Config>,
+        db_service: Arc<super::database::DatabaseService>,
+        storage_service: Arc<StorageService>,
+        budget_service: Arc<BudgetService>,
+    ) -> Self {
+        let lazy_semaphore = Arc::new(Semaphore::new(config.max_concurrent_extractions));
+        let region_semaphore = Arc::new(Semaphore::new(config.max_concurrent_region_extractions));
+
+        Self {
+            config,
+            db_service,
+            storage_service,
+            budget_service,
+            planet_source: Arc::new(Mutex::new(None)),
+            active_planet: Arc::new(Mutex::new(None)),
+            lazy_jobs: Arc::new(Mutex::new(HashMap::new())),
+            lazy_semaphore,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            regions: Arc::new(Mutex::new(HashMap::new())),
+            region_semaphore,
+            manifest_locks: Arc::new(Mutex::new(HashMap::new())),
+            delta_lock: Arc::new(Mutex::new(())),
+        }
     }
 
-    pub async fn get_planet_pmtiles_source(&self) -> Result<String, ExtractionError> {
-        // Check if a local planet pmtiles path is configured
-        if let Some(ref local_path) = self.config.planet_pmtiles_path {
-            let path = Path::new(local_path);
-            if path.exists() {
-                info!("Using local planet pmtiles file: {}", local_path);
-                return Ok(local_path.clone());
-            } else {
-                return Err(ExtractionError::PlanetUrlFailed(format!(
-                    "Local planet pmtiles file not found: {}",
-                    local_path
-                )));
+    pub fn locality_path(&self, country_code: &str, id: i64) -> PathBuf {
+        self.config
+            .localities_dir()
+            .join(country_code)
+            .join(format!("{}.pmtiles", id))
+    }
+
+    pub fn country_archive_path(&self, country_code: &str) -> PathBuf {
+        self.config
+            .countries_dir()
+            .join(format!("{}.pmtiles", country_code))
+    }
+
+    pub fn manifest_path(&self, country_code: &str) -> PathBuf {
+        self.config
+            .countries_dir()
+            .join(format!("{}.manifest.json", country_code))
+    }
+
+    pub async fn get_planet_pmtiles_source(&self) -> Result<PlanetSource, ExtractionError> {
+        let planet = self.resolve_planet_pmtiles_source().await?;
+        *self.active_planet.lock().await = Some(planet.clone());
+        Ok(planet)
+    }
+
+    /// The planet source most recently used for extraction, if any has been resolved yet.
+    pub async fn get_active_planet(&self) -> Option<PlanetSource> {
+        self.active_planet.lock().await.clone()
+    }
+
+    /// Resolves the planet build from the local planet file or the cached builds metadata, without
+    /// network access, so `/health` reports it before the first extraction.
+    pub async fn preload_active_planet(&self) {
+        let planet = match self.local_planet_pmtiles_source() {
+            Some(planet) => planet,
+            None => self
+                .read_cached_protomaps_builds()
+                .and_then(|builds| self.select_planet_build(&builds)),
+        };
+
+        match planet {
+            Ok(planet) => {
+                let mut active_planet = self.active_planet.lock().await;
+                if active_planet.is_none() {
+                    info!("Planet build at startup: {}", planet.build);
+                    *active_planet = Some(planet);
+                }
             }
+            Err(e) => warn!("Planet build not known until the first extraction: {}", e),
+        }
+    }
+
+    async fn resolve_planet_pmtiles_source(&self) -> Result<PlanetSource, ExtractionError> {
+        // Check if a local planet pmtiles path is configured
+        if let Some(planet) = self.local_planet_pmtiles_source() {
+            let planet = planet?;
+            info!("Using local planet pmtiles file: {}", planet.url);
+            return Ok(planet);
         }
 
         // Fall back to fetching the remote URL
-        info!("Fetching latest planet pmtiles URL...");
+        let builds = match self.fetch_protomaps_builds().await {
+            Ok(builds) => builds,
+            Err(e) => {
+                warn!("{}, falling back to cached builds metadata", e);
+                self.read_cached_protomaps_builds()?
+            }
+        };
+
+        let planet = self.select_planet_build(&builds)?;
+        info!("Selected planet pmtiles URL: {}", planet.url);
+
+        Ok(planet)
+    }
+
+    /// The configured local planet file, if any.
+    fn local_planet_pmtiles_source(&self) -> Option<Result<PlanetSource, ExtractionError>> {
+        let local_path = self.config.planet_pmtiles_path.as_ref()?;
+        let path = Path::new(local_path);
+
+        if !path.exists() {
+            return Some(Err(ExtractionError::PlanetUrlFailed(format!(
+                "Local planet pmtiles file not found: {}",
+                local_path
+            ))));
+        }
+
+        Some(local_build_fingerprint(path).map(|build| PlanetSource {
+            url: local_path.clone(),
+            build,
+        }))
+    }
+
+    /// Selects the pinned build, or the latest one, from the Protomaps builds metadata.
+    fn select_planet_build(
+        &self,
+        builds: &[serde_json::Value],
+    ) -> Result<PlanetSource, ExtractionError> {
+        if builds.is_empty() {
+            return Err(ExtractionError::PlanetUrlFailed(
+                "No builds found".to_string(),
+            ));
+        }
+
+        let selected_build = match self.config.protomaps_build {
+            Some(ref pinned) => pinned_build(builds, pinned).ok_or_else(|| {
+                ExtractionError::PlanetUrlFailed(format!("Pinned build not found: {}", pinned))
+            })?,
+            None => latest_build(builds.iter()).ok_or_else(|| {
+                ExtractionError::PlanetUrlFailed("No valid builds found".to_string())
+            })?,
+        };
+
+        let key = selected_build
+            .get("key")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ExtractionError::PlanetUrlFailed("Build has no key".to_string()))?;
+
+        if let Some(ref pinned) = self.config.protomaps_build {
+            info!("Using planet build {} pinned as {}", key, pinned);
+        }
+
+        let url = format!(
+            "{}/{}",
+            self.config.protomaps_base_url.trim_end_matches('/'),
+            key
+        );
+
+        Ok(PlanetSource {
+            url,
+            build: key.to_string(),
+        })
+    }
+
+    /// Fetches the Protomaps builds metadata and keeps a copy of it for offline restarts.
+    async fn fetch_protomaps_builds(&self) -> Result<Vec<serde_json::Value>, ExtractionError> {
+        info!("Fetching Protomaps builds metadata...");
 
         let response = reqwest::get(&self.config.protomaps_builds_url)
             .await
@@ -72,45 +349,85 @@ impl ExtractionService {
             )));
         }
 
-        let builds: Vec<serde_json::Value> = response.json().await.map_err(|e| {
+        let content = response.text().await.map_err(|e| {
+            ExtractionError::PlanetUrlFailed(format!("Failed to read builds: {}", e))
+        })?;
+
+        let builds: Vec<serde_json::Value> = serde_json::from_str(&content).map_err(|e| {
             ExtractionError::PlanetUrlFailed(format!("Failed to parse builds: {}", e))
         })?;
 
-        if builds.is_empty() {
-            return Err(ExtractionError::PlanetUrlFailed(
-                "No builds found".to_string(),
-            ));
+        if let Err(e) = tokio::fs::write(self.config.protomaps_builds_cache_path(), &content).await
+        {
+            warn!("Failed to cache Protomaps builds metadata: {}", e);
         }
 
-        let latest_build = builds
-            .iter()
-            .max_by_key(|build| build.get("uploaded").and_then(|v| v.as_str()).unwrap_or(""))
-            .ok_or_else(|| ExtractionError::PlanetUrlFailed("No valid builds found".to_string()))?;
+        Ok(builds)
+    }
 
-        let key = latest_build
-            .get("key")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| ExtractionError::PlanetUrlFailed("Build has no key".to_string()))?;
+    fn read_cached_protomaps_builds(&self) -> Result<Vec<serde_json::Value>, ExtractionError> {
+        let cache_path = self.config.protomaps_builds_cache_path();
 
-        let url = format!("https://build.protomaps.com/{}", key);
-        info!("Latest planet pmtiles URL: {}", url);
+        let content = std::fs::read_to_string(&cache_path).map_err(|e| {
+            ExtractionError::PlanetUrlFailed(format!(
+                "No cached builds metadata at {}: {}",
+                cache_path.display(),
+                e
+            ))
+        })?;
+
+        serde_json::from_str(&content).map_err(|e| {
+            ExtractionError::PlanetUrlFailed(format!("Failed to parse cached builds: {}", e))
+        })
+    }
+
+    /// Resolves the planet source and reuses it for on-demand extractions for up to
+    /// `PLANET_SOURCE_TTL`, so a long-running server picks up new builds.
+    async fn cached_planet_pmtiles_source(&self) -> Result<PlanetSource, ExtractionError> {
+        let mut cached = self.planet_source.lock().await;
 
-        Ok(url)
+        if let Some((planet, resolved_at)) = cached.as_ref() {
+            if resolved_at.elapsed() < PLANET_SOURCE_TTL {
+                return Ok(planet.clone());
+            }
+        }
+
+        let planet = self.get_planet_pmtiles_source().await?;
+        *cached = Some((planet.clone(), Instant::now()));
+        Ok(planet)
     }
 
     pub async fn extract_locality(
         &self,
         locality: &Locality,
-        planet_pmtiles_url: &str,
+        planet: &PlanetSource,
         country_dir: &Path,
     ) -> Result<(), ExtractionError> {
         let output_path = country_dir.join(format!("{}.pmtiles", locality.id));
 
-        if output_path.exists() {
+        if archive_exists(&output_path) {
             info!("Skipping existing file: {}", output_path.display());
             return Ok(());
         }
 
+        self.write_locality_archive(locality, planet, country_dir)
+            .await
+    }
+
+    /// Extracts a locality archive and atomically replaces any existing one.
+    async fn write_locality_archive(
+        &self,
+        locality: &Locality,
+        planet: &PlanetSource,
+        country_dir: &Path,
+    ) -> Result<(), ExtractionError> {
+        if let Err(e) = self.keep_previous_version(country_dir, locality.id).await {
+            warn!(
+                "Failed to keep the previous archive of locality {}: {}",
+                locality.id, e
+            );
+        }
+
         let bbox = format!(
             "{},{},{},{}",
             locality.min_longitude,
@@ -119,199 +436,2047 @@ impl ExtractionService {
             locality.max_latitude
         );
 
-        let args = &[
-            "extract",
-            planet_pmtiles_url,
-            output_path.to_str().unwrap(),
+        self.write_archive(
+            &locality.id.to_string(),
             &format!("--bbox={}", bbox),
+            self.config.extraction_profile(&locality.country),
+            planet,
+            country_dir,
+        )
+        .await?;
+
+        if self.config.dedup_storage {
+            let archive_path = country_dir.join(format!("{}.pmtiles", locality.id));
+
+            if let Err(e) = self.storage_service.ingest_archive(&archive_path).await {
+                // Keep the plain archive, and drop any layout of a previous extraction
+                warn!(
+                    "Failed to move locality {} into the tile store, keeping its archive: {}",
+                    locality.id, e
+                );
+                let _ = tokio::fs::remove_file(StorageService::layout_path(&archive_path)).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the current archive of a locality, or its layout, as
+    /// `.versions/{id}/{sha256}.pmtiles` so deltas can be built from it once it is replaced.
+    /// Only the latest previous version is kept.
+    async fn keep_previous_version(
+        &self,
+        country_dir: &Path,
+        id: i64,
+    ) -> Result<(), ExtractionError> {
+        let archive_path = country_dir.join(format!("{}.pmtiles", id));
+
+        let sha256 = match read_record(country_dir, id).and_then(|record| record.sha256) {
+            Some(sha256) if archive_exists(&archive_path) => sha256,
+            _ => return Ok(()),
+        };
+
+        // Older versions and the deltas built from them are dropped
+        let versions_dir = versions_dir(country_dir, id);
+        if versions_dir.exists() {
+            tokio::fs::remove_dir_all(&versions_dir).await?;
+        }
+        ensure_dir_exists(&versions_dir)?;
+
+        let version_path = versions_dir.join(format!("{}.pmtiles", sha256));
+        if archive_path.exists() {
+            // The new archive is renamed over the current one, so a hard link keeps it intact
+            if std::fs::hard_link(&archive_path, &version_path).is_err() {
+                tokio::fs::copy(&archive_path, &version_path).await?;
+            }
+        } else {
+            tokio::fs::copy(
+                StorageService::layout_path(&archive_path),
+                StorageService::layout_path(&version_path),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the patch from the archive version `from` to the current archive of a
+    /// locality, with the SHA-256 of the current archive. The patch is built on first
+    /// request and cached. Returns `None` when `from` is not the kept previous version.
+    pub async fn locality_delta(
+        &self,
+        country_code: &str,
+        id: i64,
+        from: &str,
+    ) -> Result<Option<(PathBuf, String)>, ExtractionError> {
+        if from.len() != 64 || !from.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+
+        let to = match self.locality_archive_sha256(country_code, id) {
+            Some(sha256) => sha256,
+            None => return Ok(None),
+        };
+
+        let country_dir = self.config.localities_dir().join(country_code);
+        let versions_dir = versions_dir(&country_dir, id);
+        let base_path = versions_dir.join(format!("{}.pmtiles", from));
+
+        if !archive_exists(&base_path) {
+            return Ok(None);
+        }
+
+        let delta_path = versions_dir.join(format!("{}-{}.delta.pmtiles", from, to));
+        let _guard = self.delta_lock.lock().await;
+
+        if !delta_path.exists() {
+            let archive_path = country_dir.join(format!("{}.pmtiles", id));
+            let base = self.storage_service.read_archive(&base_path).await?;
+            let target = self.storage_service.read_archive(&archive_path).await?;
+            let patch = self
+                .storage_service
+                .build_patch(base, target, from.to_string(), to.clone())
+                .await?;
+
+            let temp_path = delta_path.with_extension("tmp");
+            tokio::fs::write(&temp_path, patch).await?;
+            tokio::fs::rename(&temp_path, &delta_path).await?;
+        }
+
+        Ok(Some((delta_path, to)))
+    }
+
+    /// Extracts an area of the planet, given as the `--bbox` or `--region` argument of
+    /// `pmtiles extract`, to `{output_dir}/{name}.pmtiles`. Any existing archive is replaced
+    /// atomically, then the planet build and profile it was produced from are recorded.
+    async fn write_archive(
+        &self,
+        name: &str,
+        area_arg: &str,
+        profile: ExtractionProfile,
+        planet: &PlanetSource,
+        output_dir: &Path,
+    ) -> Result<(), ExtractionError> {
+        for country_code in self.budget_service.ensure_capacity().await? {
+            self.refresh_manifest(&country_code).await;
+        }
+
+        let output_path = output_dir.join(format!("{}.pmtiles", name));
+
+        // Extract into a temporary directory so a partially written archive is never served
+        let temp_dir = output_dir.join(".tmp");
+        ensure_dir_exists(&temp_dir)?;
+        let temp_path = temp_archive_path(&temp_dir, name);
+
+        let mut args = vec![
+            "extract".to_string(),
+            planet.url.clone(),
+            temp_path.to_string_lossy().to_string(),
+            area_arg.to_string(),
         ];
+        if let Some(min_zoom) = profile.min_zoom {
+            args.push(format!("--minzoom={}", min_zoom));
+        }
+        if let Some(max_zoom) = profile.max_zoom {
+            args.push(format!("--maxzoom={}", max_zoom));
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
 
-        info!("Extracting locality {} with bbox: {}", locality.id, bbox);
+        info!("Extracting {} with {}", name, area_arg);
         info!("Command: {} {}", &self.config.pmtiles_cmd, args.join(" "));
 
-        let output = run_command(&self.config.pmtiles_cmd, args, None).await?;
+        let output = match run_command(&self.config.pmtiles_cmd, &args, None).await {
+            Ok(output) => output,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e.into());
+            }
+        };
 
         if !output.stdout.is_empty() {
-            info!("Extraction output for {}: {}", locality.id, output.stdout);
+            info!("Extraction output for {}: {}", name, output.stdout);
         }
 
         if !output.stderr.is_empty() {
-            error!("Extraction error for {}: {}", locality.id, output.stderr);
+            error!("Extraction error for {}: {}", name, output.stderr);
         }
 
-        if output_path.exists() {
+        let temp_path = if profile.layers.is_empty() || !temp_path.exists() {
+            temp_path
+        } else {
+            self.filter_layers(name, &temp_path, &profile.layers)
+                .await?
+        };
+
+        if temp_path.exists() {
+            // Archives carry the build and profile they were extracted with, wherever they are copied
+            let embedded = self
+                .storage_service
+                .embed_metadata(
+                    &temp_path,
+                    serde_json::json!({
+                        "localitysrv": {
+                            "planet_build": planet.build,
+                            "profile": profile
+                        }
+                    }),
+                )
+                .await;
+            if let Err(e) = embedded {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e.into());
+            }
+
+            // In the tile store, the archive being replaced is only a layout
+            let previous_size = self.archive_length(&output_path).await.unwrap_or(0);
+            let size = tokio::fs::metadata(&temp_path).await?.len();
+
+            // Hash before the swap, so the archive and its record are replaced back to back
+            // and the hash served with an archive is the one of that archive
+            let sha256 = match self.storage_service.archive_digest(&temp_path).await {
+                Ok((_, sha256)) => Some(sha256),
+                Err(e) => {
+                    warn!("Failed to hash archive {}: {}", temp_path.display(), e);
+                    None
+                }
+            };
+
+            let record = ArchiveRecord {
+                build: planet.build.clone(),
+                extracted_at: unix_timestamp(),
+                profile,
+                sha256,
+            };
+            let record_json = serde_json::to_string(&record).map_err(|e| {
+                ExtractionError::FileOperationFailed(format!(
+                    "Failed to serialize archive record for {}: {}",
+                    name, e
+                ))
+            })?;
+            let temp_record_path = temp_path.with_extension("build.json");
+            if let Err(e) = tokio::fs::write(&temp_record_path, record_json).await {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e.into());
+            }
+
+            if let Err(e) = tokio::fs::rename(&temp_path, &output_path).await {
+                let _ = tokio::fs::remove_file(&temp_record_path).await;
+                return Err(e.into());
+            }
+            tokio::fs::rename(&temp_record_path, record_path(output_dir, name)).await?;
+            self.budget_service.record_written(previous_size, size);
             info!("Successfully created file: {}", output_path.display());
         } else {
             error!("Failed to create file: {}", output_path.display());
             return Err(ExtractionError::ExtractionFailed(format!(
-                "Failed to create PMTiles file for locality {}",
-                locality.id
+                "Failed to create PMTiles file for {}",
+                name
             )));
         }
 
         Ok(())
     }
 
-    pub async fn extract_localities(
+    /// Keeps only the given vector layers of an extracted archive. Returns the path of
+    /// the filtered archive, which replaces the unfiltered one.
+    async fn filter_layers(
         &self,
-        country_codes: &[String],
-    ) -> Result<(), ExtractionError> {
-        let planet_url = self.get_planet_pmtiles_source().await?;
+        name: &str,
+        input_path: &Path,
+        layers: &[String],
+    ) -> Result<PathBuf, ExtractionError> {
+        let filtered_path = input_path.with_extension("layers.pmtiles");
+        let filtered_path_str = filtered_path.to_string_lossy().to_string();
+        let input_path_str = input_path.to_string_lossy().to_string();
 
-        for country_code in country_codes {
-            info!("Processing country: {}", country_code);
+        let mut args = vec!["-f", "-pk", "-o", filtered_path_str.as_str()];
+        for layer in layers {
+            args.push("-l");
+            args.push(layer.as_str());
+        }
+        args.push(input_path_str.as_str());
 
-            let country_dir = self.config.localities_dir().join(country_code);
-            ensure_dir_exists(&country_dir)?;
+        info!("Filtering layers of {}: {}", name, layers.join(", "));
 
-            let localities = self
-                .db_service
-                .get_country_localities(country_code)
-                .await
-                .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?;
+        let result = run_command(&self.config.tile_join_cmd, &args, None).await;
+        let _ = tokio::fs::remove_file(input_path).await;
 
-            if localities.is_empty() {
-                info!("No localities found for country: {}", country_code);
-                continue;
-            }
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&filtered_path).await;
+            return Err(e.into());
+        }
 
-            info!(
-                "Found {} localities for country: {}",
-                localities.len(),
-                country_code
-            );
+        Ok(filtered_path)
+    }
 
-            let mut existing_count = 0;
-            for locality in &localities {
-                let output_path = country_dir.join(format!("{}.pmtiles", locality.id));
-                if output_path.exists() {
-                    existing_count += 1;
-                }
+    /// Enqueues the extraction of a single locality unless its archive already exists
+    /// or an extraction for it is already running. Returns `None` for unknown localities.
+    pub async fn request_locality_extraction(
+        &self,
+        country_code: &str,
+        id: i64,
+    ) -> Result<Option<ExtractionStatus>, ExtractionError> {
+        if archive_exists(&self.locality_path(country_code, id)) {
+            return Ok(Some(ExtractionStatus::Ready));
+        }
+
+        let key = (country_code.to_string(), id);
+
+        if let Some(ExtractionStatus::Pending) = self.lazy_jobs.lock().await.get(&key) {
+            return Ok(Some(ExtractionStatus::Pending));
+        }
+
+        let locality = match self
+            .db_service
+            .get_locality(country_code, id)
+            .await
+            .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?
+        {
+            Some(locality) => locality,
+            None => return Ok(None),
+        };
+
+        // Another request may have enqueued the job while the database was queried
+        if !claim_lazy_job(&mut *self.lazy_jobs.lock().await, &key) {
+            return Ok(Some(ExtractionStatus::Pending));
+        }
+
+        info!(
+            "Enqueuing on-demand extraction for locality {} ({})",
+            id, country_code
+        );
+
+        let extraction_service = self.clone();
+        let country_dir = self.config.localities_dir().join(country_code);
+
+        tokio::spawn(async move {
+            let _permit = extraction_service.lazy_semaphore.acquire().await.unwrap();
+
+            let result = async {
+                ensure_dir_exists(&country_dir)?;
+                let planet = extraction_service.cached_planet_pmtiles_source().await?;
+                extraction_service
+                    .extract_locality(&locality, &planet, &country_dir)
+                    .await
             }
+            .await;
 
-            let total_count = localities.len();
-            let remaining_count = total_count - existing_count;
+            if result.is_ok() {
+                extraction_service.refresh_manifest(&key.0).await;
+            }
 
-            if remaining_count == 0 {
-                info!(
-                    "All {} localities already exist for country: {}",
-                    total_count, country_code
-                );
-                continue;
+            if let Err(e) = &result {
+                warn!("On-demand extraction failed for locality {}: {}", key.1, e);
             }
 
-            info!(
-                "Progress: {}/{} localities already exist, {} remaining to extract",
-                existing_count, total_count, remaining_count
-            );
+            finish_lazy_job(&mut *extraction_service.lazy_jobs.lock().await, key, result);
+        });
 
-            let semaphore = Arc::new(Semaphore::new(self.config.max_concurrent_extractions));
-            let mut tasks = Vec::new();
-            let completed_count = Arc::new(std::sync::atomic::AtomicUsize::new(existing_count));
-
-            for locality in localities {
-                let planet_url = planet_url.clone();
-                let country_dir = country_dir.clone();
-                let semaphore = semaphore.clone();
-                let extraction_service = self.clone();
-                let completed_count = completed_count.clone();
-
-                let task = tokio::spawn(async move {
-                    let _permit = semaphore.acquire().await.unwrap();
-                    let result = extraction_service
-                        .extract_locality(&locality, &planet_url, &country_dir)
-                        .await;
-
-                    // Update progress counter
-                    if result.is_ok() {
-                        let current =
-                            completed_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                        // Use carriage return to overwrite the line
-                        info!(
-                            "Progress: {}/{} localities extracted for {}",
-                            current + 1,
-                            total_count,
-                            locality.country
-                        );
-                    }
+        Ok(Some(ExtractionStatus::Pending))
+    }
+
+    pub async fn get_locality_extraction_status(
+        &self,
+        country_code: &str,
+        id: i64,
+    ) -> ExtractionStatus {
+        if archive_exists(&self.locality_path(country_code, id)) {
+            return ExtractionStatus::Ready;
+        }
+
+        self.lazy_jobs
+            .lock()
+            .await
+            .get(&(country_code.to_string(), id))
+            .cloned()
+            .unwrap_or(ExtractionStatus::Missing)
+    }
 
-                    result
-                });
+    /// Enqueues a one-off extraction of a custom region, given either as a
+    /// `[min_lon, min_lat, max_lon, max_lat]` bbox or a GeoJSON polygon. Returns the job id.
+    pub async fn request_region_extraction(
+        &self,
+        bbox: Option<[f64; 4]>,
+        geometry: Option<serde_json::Value>,
+    ) -> Result<String, ExtractionError> {
+        let (bounds, area) =
+            region_bounds(bbox, geometry.as_ref(), self.config.max_region_area_km2)?;
+        let [min_longitude, min_latitude, max_longitude, max_latitude] = bounds;
 
-                tasks.push(task);
+        let id = new_region_id();
+        {
+            let mut regions = self.regions.lock().await;
+            let queued = regions
+                .values()
+                .filter(|(status, _)| *status == ExtractionStatus::Pending)
+                .count();
+            if queued >= self.config.max_queued_regions {
+                return Err(ExtractionError::RegionQueueFull(queued));
             }
+            regions.insert(id.clone(), (ExtractionStatus::Pending, unix_timestamp()));
+        }
 
-            let results = join_all(tasks).await;
+        info!("Enqueuing extraction of region {} ({:.0} km²)", id, area);
 
-            let mut has_errors = false;
-            for result in results {
-                match result {
-                    Ok(Ok(())) => {} // Success
-                    Ok(Err(e)) => {
-                        error!("Extraction task failed: {}", e);
-                        has_errors = true;
-                    }
-                    Err(e) => {
-                        error!("Extraction task panicked: {:?}", e);
-                        has_errors = true;
+        let extraction_service = self.clone();
+        let regions_dir = self.config.regions_dir();
+        let region_id = id.clone();
+
+        tokio::spawn(async move {
+            let _region_permit = extraction_service.region_semaphore.acquire().await.unwrap();
+            let _permit = extraction_service.lazy_semaphore.acquire().await.unwrap();
+            let geometry_path = regions_dir
+                .join(".tmp")
+                .join(format!("{}.geojson", region_id));
+
+            let result = async {
+                ensure_dir_exists(&regions_dir.join(".tmp"))?;
+
+                let area_arg = match geometry {
+                    Some(geometry) => {
+                        tokio::fs::write(&geometry_path, geometry.to_string()).await?;
+                        format!("--region={}", geometry_path.display())
                     }
-                }
+                    None => format!(
+                        "--bbox={},{},{},{}",
+                        min_longitude, min_latitude, max_longitude, max_latitude
+                    ),
+                };
+
+                let planet = extraction_service.cached_planet_pmtiles_source().await?;
+                extraction_service
+                    .write_archive(
+                        &region_id,
+                        &area_arg,
+                        extraction_service.config.extraction_profile.clone(),
+                        &planet,
+                        &regions_dir,
+                    )
+                    .await
             }
+            .await;
 
-            if has_errors {
-                return Err(ExtractionError::ExtractionFailed(format!(
-                    "Some extraction tasks failed for country: {}",
-                    country_code
-                )));
+            let _ = tokio::fs::remove_file(&geometry_path).await;
+
+            let mut regions = extraction_service.regions.lock().await;
+            match result {
+                Ok(()) => {
+                    regions.remove(&region_id);
+                }
+                Err(e) => {
+                    warn!("Extraction of region {} failed: {}", region_id, e);
+                    regions.insert(
+                        region_id,
+                        (
+                            ExtractionStatus::Failed {
+                                error: e.to_string(),
+                            },
+                            unix_timestamp(),
+                        ),
+                    );
+                }
             }
-        }
+        });
 
-        Ok(())
+        Ok(id)
     }
 
-    pub async fn ensure_all_localities_present(&self) -> Result<(), ExtractionError> {
-        let countries = self.config.target_countries.clone();
-
-        if countries.is_empty() {
-            return Err(ExtractionError::ExtractionFailed(
-                "No target countries specified".to_string(),
-            ));
+    /// The archive path of a region job, or `None` if the id is not a valid job id.
+    pub fn region_archive_path(&self, id: &str) -> Option<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
         }
 
-        self.extract_localities(&countries).await
+        Some(self.config.regions_dir().join(format!("{}.pmtiles", id)))
     }
 
-    async fn get_pmtiles_file_count(&self, country_code: &str) -> Result<u32, ExtractionError> {
-        let country_dir = self.config.localities_dir().join(country_code);
-
-        if !country_dir.exists() {
-            return Ok(0);
+    pub async fn get_region_extraction_status(&self, id: &str) -> ExtractionStatus {
+        match self.region_archive_path(id) {
+            Some(path) if path.exists() => ExtractionStatus::Ready,
+            Some(_) => self
+                .regions
+                .lock()
+                .await
+                .get(id)
+                .map(|(status, _)| status.clone())
+                .unwrap_or(ExtractionStatus::Missing),
+            None => ExtractionStatus::Missing,
         }
+    }
 
-        let mut count = 0;
-        for entry in std::fs::read_dir(country_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+    /// Removes region archives older than `REGION_ARCHIVE_TTL_SECS` in the background, along
+    /// with the status of failed region jobs.
+    pub fn start_region_sweep(&self) {
+        let Some(ttl) = self.config.region_archive_ttl_secs else {
+            return;
+        };
+        let extraction_service = self.clone();
 
-            if path.extension().and_then(|s| s.to_str()) == Some("pmtiles") {
-                count += 1;
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(REGION_SWEEP_INTERVAL.min(Duration::from_secs(ttl)));
+            loop {
+                interval.tick().await;
+                if let Err(e) = extraction_service.sweep_region_archives(ttl).await {
+                    warn!("Failed to remove expired region archives: {}", e);
+                }
             }
-        }
-
-        Ok(count)
+        });
     }
 
-    pub async fn batch_get_pmtiles_file_count(
-        &self,
-        country_codes: &[String],
-    ) -> Result<HashMap<String, u32>, ExtractionError> {
-        let mut counts = HashMap::new();
+    async fn sweep_region_archives(&self, ttl: u64) -> Result<(), ExtractionError> {
+        let now = unix_timestamp();
+        self.regions.lock().await.retain(|_, (status, updated_at)| {
+            *status == ExtractionStatus::Pending || now.saturating_sub(*updated_at) <= ttl
+        });
 
-        for country_code in country_codes {
-            let count = self.get_pmtiles_file_count(country_code).await?;
-            counts.insert(country_code.clone(), count);
+        let regions_dir = self.config.regions_dir();
+        if !regions_dir.exists() {
+            return Ok(());
         }
 
-        Ok(counts)
+        let mut removed = 0;
+        let mut entries = tokio::fs::read_dir(&regions_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or_default();
+            if age.as_secs() <= ttl {
+                continue;
+            }
+
+            tokio::fs::remove_file(entry.path()).await?;
+            self.budget_service.record_written(metadata.len(), 0);
+            removed += 1;
+        }
+
+        if removed > 0 {
+            info!("Removed {} expired region files", removed);
+        }
+
+        Ok(())
+    }
+
+    pub async fn extract_localities(
+        &self,
+        country_codes: &[String],
+    ) -> Result<(), ExtractionError> {
+        let planet = self.get_planet_pmtiles_source().await?;
+
+        for country_code in country_codes {
+            info!("Processing country: {}", country_code);
+
+            let localities = self
+                .db_service
+                .get_country_localities(country_code)
+                .await
+                .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?;
+
+            if localities.is_empty() {
+                info!("No localities found for country: {}", country_code);
+                continue;
+            }
+
+            info!(
+                "Found {} localities for country: {}",
+                localities.len(),
+                country_code
+            );
+
+            let country_region = localities_region(&localities);
+            self.run_country_job(country_code, localities, &planet, false)
+                .await?;
+
+            if let Some(region) = country_region {
+                self.extract_country_archive(country_code, &region, &planet, false)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Extracts the missing and invalid archives found by `check_country_archives`,
+    /// replacing invalid ones, along with missing country archives.
+    pub async fn extract_missing_localities(
+        &self,
+        checks: &[CountryArchiveCheck],
+    ) -> Result<(), ExtractionError> {
+        let planet = self.get_planet_pmtiles_source().await?;
+
+        for check in checks.iter().filter(|check| !check.is_complete()) {
+            let country_code = &check.country_code;
+
+            let localities = self
+                .db_service
+                .get_country_localities(country_code)
+                .await
+                .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?;
+
+            let country_region = localities_region(&localities);
+            let localities: Vec<Locality> = localities
+                .into_iter()
+                .filter(|locality| {
+                    check.missing.contains(&locality.id) || check.invalid.contains(&locality.id)
+                })
+                .collect();
+
+            if !localities.is_empty() {
+                info!(
+                    "Extracting {} missing and {} invalid localities for country: {}",
+                    check.missing.len(),
+                    check.invalid.len(),
+                    country_code
+                );
+                self.run_country_job(country_code, localities, &planet, true)
+                    .await?;
+            }
+
+            if let Some(region) = country_region {
+                self.extract_country_archive(country_code, &region, &planet, false)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Extracts the archive of a whole country, covering the union of its locality extents.
+    /// It is only extracted once every locality archive of the country has been.
+    async fn extract_country_archive(
+        &self,
+        country_code: &str,
+        region: &serde_json::Value,
+        planet: &PlanetSource,
+        replace_existing: bool,
+    ) -> Result<(), ExtractionError> {
+        let output_path = self.country_archive_path(country_code);
+
+        if !replace_existing && output_path.exists() {
+            info!("Skipping existing file: {}", output_path.display());
+            return Ok(());
+        }
+
+        let countries_dir = self.config.countries_dir();
+        ensure_dir_exists(&countries_dir)?;
+
+        let temp_dir = countries_dir.join(".tmp");
+        ensure_dir_exists(&temp_dir)?;
+        let region_path = temp_dir.join(format!("{}.geojson", country_code));
+        tokio::fs::write(&region_path, region.to_string()).await?;
+
+        info!("Extracting country archive for: {}", country_code);
+        let result = self
+            .write_archive(
+                country_code,
+                &format!("--region={}", region_path.display()),
+                self.config.extraction_profile(country_code),
+                planet,
+                &countries_dir,
+            )
+            .await;
+
+        let _ = tokio::fs::remove_file(&region_path).await;
+        result
+    }
+
+    /// Re-extracts the existing locality and country archives of the given countries that were produced by
+    /// another planet build, have no build record, or are older than the configured max age.
+    pub async fn refresh_stale_localities(
+        &self,
+        country_codes: &[String],
+    ) -> Result<(), ExtractionError> {
+        let planet = self.get_planet_pmtiles_source().await?;
+        let max_age = archive_max_age(self.config.archive_max_age_days);
+
+        for country_code in country_codes {
+            let country_dir = self.config.localities_dir().join(country_code);
+            let profile = self.config.extraction_profile(country_code);
+
+            let localities = self
+                .db_service
+                .get_country_localities(country_code)
+                .await
+                .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?;
+
+            if self.country_archive_path(country_code).exists()
+                && is_archive_stale(
+                    &self.config.countries_dir(),
+                    country_code,
+                    &planet,
+                    &profile,
+                    max_age,
+                )
+            {
+                if let Some(region) = localities_region(&localities) {
+                    self.extract_country_archive(country_code, &region, &planet, true)
+                        .await?;
+                }
+            }
+
+            let stale_localities: Vec<Locality> = localities
+                .into_iter()
+                .filter(|locality| {
+                    archive_exists(&country_dir.join(format!("{}.pmtiles", locality.id)))
+                        && is_archive_stale(&country_dir, locality.id, &planet, &profile, max_age)
+                })
+                .collect();
+
+            if stale_localities.is_empty() {
+                info!("All archives are up to date for country: {}", country_code);
+                continue;
+            }
+
+            info!(
+                "Found {} stale archives for country: {}, refreshing from build {}",
+                stale_localities.len(),
+                country_code,
+                planet.build
+            );
+
+            self.run_country_job(country_code, stale_localities, &planet, true)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts refreshing the stale archives of the given countries in the background.
+    pub fn start_refresh(&self, country_codes: Vec<String>) {
+        let extraction_service = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = extraction_service
+                .refresh_stale_localities(&country_codes)
+                .await
+            {
+                error!("Background refresh failed: {}", e);
+            }
+
+            extraction_service.collect_tile_garbage().await;
+        });
+    }
+
+    /// Removes the stored tiles no archive references anymore, logging failures.
+    pub async fn collect_tile_garbage(&self) {
+        match self.storage_service.collect_garbage().await {
+            Ok((0, _)) => {}
+            Ok((blobs, bytes)) => {
+                info!(
+                    "Removed {} unreferenced tile blobs ({} bytes)",
+                    blobs, bytes
+                );
+                self.budget_service.record_written(bytes, 0);
+            }
+            Err(e) => warn!("Failed to remove unreferenced tile blobs: {}", e),
+        }
+    }
+
+    async fn run_country_job(
+        &self,
+        country_code: &str,
+        localities: Vec<Locality>,
+        planet: &PlanetSource,
+        replace_existing: bool,
+    ) -> Result<(), ExtractionError> {
+        let country_dir = self.config.localities_dir().join(country_code);
+        ensure_dir_exists(&country_dir)?;
+
+        let mut existing_count = 0;
+        if !replace_existing {
+            for locality in &localities {
+                let output_path = country_dir.join(format!("{}.pmtiles", locality.id));
+                if archive_exists(&output_path) {
+                    existing_count += 1;
+                }
+            }
+        }
+
+        let total_count = localities.len();
+        let remaining_count = total_count - existing_count;
+
+        let control_rx = self
+            .register_job(country_code, total_count, existing_count)
+            .await?;
+
+        if remaining_count == 0 {
+            info!(
+                "All {} localities already exist for country: {}",
+                total_count, country_code
+            );
+            self.finish_job(country_code).await;
+            // The manifest may predate archives extracted by another job, or be missing
+            self.refresh_manifest(country_code).await;
+            return Ok(());
+        }
+
+        info!(
+            "Progress: {}/{} localities already exist, {} remaining to extract",
+            existing_count, total_count, remaining_count
+        );
+
+        let semaphore = Arc::new(Semaphore::new(self.config.max_concurrent_extractions));
+        let mut tasks = Vec::new();
+
+        for locality in localities {
+            let planet = planet.clone();
+            let country_dir = country_dir.clone();
+            let semaphore = semaphore.clone();
+            let extraction_service = self.clone();
+            let mut control_rx = control_rx.clone();
+
+            let task = tokio::spawn(async move {
+                let _permit = semaphore.acquire().await.unwrap();
+
+                if !wait_until_runnable(&mut control_rx).await {
+                    return Ok(());
+                }
+
+                let result = if replace_existing {
+                    extraction_service
+                        .write_locality_archive(&locality, &planet, &country_dir)
+                        .await
+                } else {
+                    extraction_service
+                        .extract_locality(&locality, &planet, &country_dir)
+                        .await
+                };
+
+                extraction_service
+                    .record_job_progress(&locality, result.is_ok())
+                    .await;
+
+                result
+            });
+
+            tasks.push(task);
+        }
+
+        let results = join_all(tasks).await;
+
+        let mut has_errors = false;
+        for result in results {
+            match result {
+                Ok(Ok(())) => {} // Success
+                Ok(Err(e)) => {
+                    error!("Extraction task failed: {}", e);
+                    has_errors = true;
+                }
+                Err(e) => {
+                    error!("Extraction task panicked: {:?}", e);
+                    has_errors = true;
+                }
+            }
+        }
+
+        self.finish_job(country_code).await;
+
+        if let Err(e) = self.budget_service.refresh_usage().await {
+            warn!("Failed to refresh disk usage: {}", e);
+        }
+
+        self.refresh_manifest(country_code).await;
+
+        if has_errors {
+            return Err(ExtractionError::ExtractionFailed(format!(
+                "Some extraction tasks failed for country: {}",
+                country_code
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn register_job(
+        &self,
+        country_code: &str,
+        total: usize,
+        completed: usize,
+    ) -> Result<watch::Receiver<JobControl>, ExtractionError> {
+        let mut jobs = self.jobs.lock().await;
+
+        if let Some(handle) = jobs.get(country_code) {
+            if handle.job.state.is_active() {
+                return Err(ExtractionError::ExtractionFailed(format!(
+                    "An extraction job is already running for country: {}",
+                    country_code
+                )));
+            }
+        }
+
+        let (handle, control_rx) = JobHandle::new(country_code, total, completed);
+        jobs.insert(country_code.to_string(), handle);
+
+        Ok(control_rx)
+    }
+
+    async fn record_job_progress(&self, locality: &Locality, success: bool) {
+        let mut jobs = self.jobs.lock().await;
+
+        if let Some(handle) = jobs.get_mut(&locality.country) {
+            handle.record_progress(locality.id, success);
+        }
+    }
+
+    async fn finish_job(&self, country_code: &str) {
+        let mut jobs = self.jobs.lock().await;
+
+        if let Some(handle) = jobs.get_mut(country_code) {
+            handle.finish();
+        }
+    }
+
+    pub async fn get_jobs(&self) -> Vec<ExtractionJob> {
+        let mut jobs: Vec<ExtractionJob> = self
+            .jobs
+            .lock()
+            .await
+            .values()
+            .map(|handle| handle.job.clone())
+            .collect();
+        jobs.sort_by(|a, b| a.country_code.cmp(&b.country_code));
+        jobs
+    }
+
+    pub async fn get_job(&self, country_code: &str) -> Option<ExtractionJob> {
+        self.jobs
+            .lock()
+            .await
+            .get(country_code)
+            .map(|handle| handle.job.clone())
+    }
+
+    /// Starts extracting the given countries in the background.
+    pub fn start_extraction(&self, country_codes: Vec<String>) {
+        let extraction_service = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = extraction_service.extract_localities(&country_codes).await {
+                error!("Background extraction failed: {}", e);
+            }
+        });
+    }
+
+    pub async fn pause_job(&self, country_code: &str) -> Result<ExtractionJob, ExtractionError> {
+        self.control_job(
+            country_code,
+            JobState::Running,
+            JobControl::Pause,
+            JobState::Paused,
+        )
+        .await
+    }
+
+    pub async fn resume_job(&self, country_code: &str) -> Result<ExtractionJob, ExtractionError> {
+        self.control_job(
+            country_code,
+            JobState::Paused,
+            JobControl::Run,
+            JobState::Running,
+        )
+        .await
+    }
+
+    /// Cancels a job; extractions already in flight are allowed to finish.
+    pub async fn cancel_job(&self, country_code: &str) -> Result<ExtractionJob, ExtractionError> {
+        self.jobs
+            .lock()
+            .await
+            .get_mut(country_code)
+            .ok_or_else(|| ExtractionError::JobNotFound(country_code.to_string()))?
+            .cancel()
+    }
+
+    async fn control_job(
+        &self,
+        country_code: &str,
+        expected: JobState,
+        control: JobControl,
+        state: JobState,
+    ) -> Result<ExtractionJob, ExtractionError> {
+        self.jobs
+            .lock()
+            .await
+            .get_mut(country_code)
+            .ok_or_else(|| ExtractionError::JobNotFound(country_code.to_string()))?
+            .transition(expected, control, state)
+    }
+
+    /// Re-runs the extraction of the localities that failed in the last job of a country.
+    pub async fn retry_failed_localities(
+        &self,
+        country_code: &str,
+    ) -> Result<usize, ExtractionError> {
+        let failed_ids = {
+            let jobs = self.jobs.lock().await;
+            let handle = jobs
+                .get(country_code)
+                .ok_or_else(|| ExtractionError::JobNotFound(country_code.to_string()))?;
+
+            if handle.job.state.is_active() {
+                return Err(ExtractionError::InvalidJobState(format!(
+                    "Job for {} is still running",
+                    country_code
+                )));
+            }
+
+            handle.job.failed.clone()
+        };
+
+        let localities: Vec<Locality> = self
+            .db_service
+            .get_country_localities(country_code)
+            .await
+            .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?
+            .into_iter()
+            .filter(|locality| failed_ids.contains(&locality.id))
+            .collect();
+
+        let retry_count = localities.len();
+        if retry_count == 0 {
+            return Ok(0);
+        }
+
+        let extraction_service = self.clone();
+        let country_code = country_code.to_string();
+
+        tokio::spawn(async move {
+            let result = async {
+                let planet = extraction_service.get_planet_pmtiles_source().await?;
+                extraction_service
+                    .run_country_job(&country_code, localities, &planet, false)
+                    .await
+            }
+            .await;
+
+            if let Err(e) = result {
+                error!(
+                    "Retry of failed localities for {} failed: {}",
+                    country_code, e
+                );
+            }
+        });
+
+        Ok(retry_count)
+    }
+
+    pub async fn ensure_all_localities_present(&self) -> Result<(), ExtractionError> {
+        let countries = self.config.target_countries.clone();
+
+        if countries.is_empty() {
+            return Err(ExtractionError::ExtractionFailed(
+                "No target countries specified".to_string(),
+            ));
+        }
+
+        self.extract_localities(&countries).await
+    }
+
+    /// Finds the archives of localities that are no longer in the database, then deletes
+    /// or quarantines them according to `mode`.
+    pub async fn prune_orphaned_archives(
+        &self,
+        mode: PruneMode,
+    ) -> Result<Vec<PruneReport>, ExtractionError> {
+        let localities_dir = self.config.localities_dir();
+        let mut reports = Vec::new();
+
+        if !localities_dir.exists() {
+            return Ok(reports);
+        }
+
+        let mut country_codes = Vec::new();
+        for entry in std::fs::read_dir(&localities_dir)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                country_codes.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        country_codes.sort();
+
+        for country_code in country_codes {
+            let known_ids: HashSet<i64> = self
+                .db_service
+                .get_country_localities(&country_code)
+                .await
+                .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?
+                .into_iter()
+                .map(|locality| locality.id)
+                .collect();
+
+            // Never prune a whole country because of a missing or mismatched database
+            if known_ids.is_empty() {
+                warn!(
+                    "No localities found in the database for {}, skipping prune",
+                    country_code
+                );
+                continue;
+            }
+
+            let country_dir = localities_dir.join(&country_code);
+            let mut orphans = Vec::new();
+            let mut orphan_paths = Vec::new();
+            let mut bytes = 0;
+
+            for entry in std::fs::read_dir(&country_dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().to_string();
+
+                if let Some(id) = orphaned_archive_id(&file_name, &known_ids) {
+                    if !orphans.contains(&id) {
+                        orphans.push(id);
+                    }
+                    bytes += entry.metadata()?.len();
+                    orphan_paths.push(entry.path());
+                }
+            }
+
+            if orphans.is_empty() {
+                continue;
+            }
+            orphans.sort_unstable();
+
+            // Previous versions only serve deltas, so they are dropped with their archive
+            if mode != PruneMode::DryRun {
+                for id in &orphans {
+                    let versions_dir = versions_dir(&country_dir, *id);
+                    if versions_dir.exists() {
+                        tokio::fs::remove_dir_all(&versions_dir).await?;
+                    }
+                }
+            }
+
+            match mode {
+                PruneMode::DryRun => {}
+                PruneMode::Delete => {
+                    for path in &orphan_paths {
+                        tokio::fs::remove_file(path).await?;
+                    }
+                }
+                PruneMode::Quarantine => {
+                    let quarantine_dir = self.config.quarantine_dir().join(&country_code);
+                    ensure_dir_exists(&quarantine_dir)?;
+
+                    for path in &orphan_paths {
+                        if let Some(file_name) = path.file_name() {
+                            tokio::fs::rename(path, quarantine_dir.join(file_name)).await?;
+                        }
+                    }
+                }
+            }
+
+            if mode != PruneMode::DryRun && self.manifest_path(&country_code).exists() {
+                self.refresh_manifest(&country_code).await;
+            }
+
+            reports.push(PruneReport {
+                country_code,
+                orphans,
+                bytes,
+            });
+        }
+
+        if mode != PruneMode::DryRun {
+            self.collect_tile_garbage().await;
+
+            if let Err(e) = self.budget_service.refresh_usage().await {
+                warn!("Failed to refresh disk usage: {}", e);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Regenerates the manifest of a country, logging failures. Manifests are only a cache
+    /// of what is on disk, so a failure never fails the extraction that triggered it.
+    async fn refresh_manifest(&self, country_code: &str) {
+        if let Err(e) = self.update_manifest(country_code).await {
+            warn!("Failed to update manifest for {}: {}", country_code, e);
+        }
+    }
+
+    /// Lists the available locality archives of a country and caches the result on disk.
+    /// Hashes are carried over from the previous manifest for archives that have not been
+    /// re-extracted since.
+    pub async fn update_manifest(
+        &self,
+        country_code: &str,
+    ) -> Result<CountryManifest, ExtractionError> {
+        let manifest_lock = self
+            .manifest_locks
+            .lock()
+            .await
+            .entry(country_code.to_string())
+            .or_default()
+            .clone();
+        let _guard = manifest_lock.lock().await;
+        let manifest_path = self.manifest_path(country_code);
+
+        let previous: HashMap<i64, ManifestEntry> = std::fs::read_to_string(&manifest_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CountryManifest>(&content).ok())
+            .map(|manifest| {
+                manifest
+                    .archives
+                    .into_iter()
+                    .map(|entry| (entry.id, entry))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let localities = self
+            .db_service
+            .get_country_localities(country_code)
+            .await
+            .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?;
+
+        let country_dir = self.config.localities_dir().join(country_code);
+        let mut archives = Vec::new();
+
+        for locality in localities {
+            let archive_path = country_dir.join(format!("{}.pmtiles", locality.id));
+            if !archive_exists(&archive_path) {
+                continue;
+            }
+
+            let record = read_record(&country_dir, locality.id);
+            let build = record.as_ref().map(|record| record.build.clone());
+            let extracted_at = record.as_ref().map(|record| record.extracted_at);
+            let recorded_sha256 = record.and_then(|record| record.sha256);
+
+            let reusable = previous
+                .get(&locality.id)
+                .filter(|entry| extracted_at.is_some() && entry.extracted_at == extracted_at);
+
+            let (size, sha256) = match (reusable, recorded_sha256) {
+                (Some(entry), _) => (entry.size, entry.sha256.clone()),
+                (None, Some(sha256)) => (self.archive_length(&archive_path).await?, sha256),
+                (None, None) => match self.storage_service.archive_digest(&archive_path).await {
+                    Ok(digest) => digest,
+                    Err(e) => {
+                        warn!("Failed to hash archive of locality {}: {}", locality.id, e);
+                        continue;
+                    }
+                },
+            };
+
+            archives.push(ManifestEntry {
+                id: locality.id,
+                name: locality.name,
+                bbox: [
+                    locality.min_longitude,
+                    locality.min_latitude,
+                    locality.max_longitude,
+                    locality.max_latitude,
+                ],
+                size,
+                sha256,
+                build,
+                extracted_at,
+            });
+        }
+
+        let manifest = CountryManifest {
+            country_code: country_code.to_string(),
+            generated_at: unix_timestamp(),
+            archives,
+        };
+
+        let temp_dir = self.config.countries_dir().join(".tmp");
+        ensure_dir_exists(&temp_dir)?;
+        let temp_path = temp_dir.join(format!("{}.manifest.json", country_code));
+
+        let content = serde_json::to_string(&manifest)
+            .map_err(|e| ExtractionError::FileOperationFailed(e.to_string()))?;
+        tokio::fs::write(&temp_path, content).await?;
+        tokio::fs::rename(&temp_path, &manifest_path).await?;
+
+        Ok(manifest)
+    }
+
+    /// The length of an archive, plain or virtual.
+    async fn archive_length(&self, archive_path: &Path) -> Result<u64, ExtractionError> {
+        match tokio::fs::metadata(archive_path).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(_) => Ok(self
+                .storage_service
+                .read_layout(archive_path)
+                .await?
+                .length()),
+        }
+    }
+
+    /// Returns the SHA-256 recorded when the archive of a locality was extracted.
+    pub fn locality_archive_sha256(&self, country_code: &str, id: i64) -> Option<String> {
+        let country_dir = self.config.localities_dir().join(country_code);
+        read_record(&country_dir, id).and_then(|record| record.sha256)
+    }
+
+    /// Compares the localities of a country in the database with its archives on disk.
+    pub async fn check_country_archives(
+        &self,
+        country_code: &str,
+    ) -> Result<CountryArchiveCheck, ExtractionError> {
+        let expected_ids: HashSet<i64> = self
+            .db_service
+            .get_country_localities(country_code)
+            .await
+            .map_err(|e| ExtractionError::DatabaseError(e.to_string()))?
+            .into_iter()
+            .map(|locality| locality.id)
+            .collect();
+
+        // An archive is either a plain file or, in the tile store, a layout
+        let country_dir = self.config.localities_dir().join(country_code);
+        let mut present_ids = HashSet::new();
+
+        if country_dir.exists() {
+            for entry in std::fs::read_dir(&country_dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+
+                if let Some(id) = file_name
+                    .strip_suffix(".pmtiles")
+                    .or_else(|| file_name.strip_suffix(".layout.json"))
+                    .and_then(|id| id.parse::<i64>().ok())
+                {
+                    present_ids.insert(id);
+                }
+            }
+        }
+
+        let mut missing: Vec<i64> = expected_ids.difference(&present_ids).copied().collect();
+        let mut orphaned: Vec<i64> = present_ids.difference(&expected_ids).copied().collect();
+        let mut invalid = Vec::new();
+
+        for id in expected_ids.intersection(&present_ids) {
+            let archive_path = country_dir.join(format!("{}.pmtiles", id));
+            if !self.storage_service.is_valid_archive(&archive_path).await {
+                invalid.push(*id);
+            }
+        }
+
+        missing.sort_unstable();
+        orphaned.sort_unstable();
+        invalid.sort_unstable();
+
+        Ok(CountryArchiveCheck {
+            country_code: country_code.to_string(),
+            expected: expected_ids.len(),
+            present: present_ids.len(),
+            missing,
+            orphaned,
+            invalid,
+            has_country_archive: self.country_archive_path(country_code).exists(),
+        })
+    }
+
+    pub async fn batch_check_country_archives(
+        &self,
+        country_codes: &[String],
+    ) -> Result<Vec<CountryArchiveCheck>, ExtractionError> {
+        let mut checks = Vec::new();
+
+        for country_code in country_codes {
+            checks.push(self.check_country_archives(country_code).await?);
+        }
+
+        Ok(checks)
+    }
+}
+
+/// Blocks while the job is paused. Returns `false` once the job has been cancelled.
+async fn wait_until_runnable(control_rx: &mut watch::Receiver<JobControl>) -> bool {
+    loop {
+        match *control_rx.borrow_and_update() {
+            JobControl::Run => return true,
+            JobControl::Cancel => return false,
+            JobControl::Pause => {}
+        }
+
+        if control_rx.changed().await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Marks the on-demand extraction of a locality as pending. Returns `false` when one
+/// is already pending; failed extractions are enqueued again.
+fn claim_lazy_job(
+    jobs: &mut HashMap<(String, i64), ExtractionStatus>,
+    key: &(String, i64),
+) -> bool {
+    if let Some(ExtractionStatus::Pending) = jobs.get(key) {
+        return false;
+    }
+    jobs.insert(key.clone(), ExtractionStatus::Pending);
+    true
+}
+
+/// Records the outcome of an on-demand extraction. Successful jobs are forgotten, as the
+/// archive on disk then reports them ready, while failures are kept until the next request.
+fn finish_lazy_job(
+    jobs: &mut HashMap<(String, i64), ExtractionStatus>,
+    key: (String, i64),
+    result: Result<(), ExtractionError>,
+) {
+    match result {
+        Ok(()) => {
+            jobs.remove(&key);
+        }
+        Err(e) => {
+            jobs.insert(
+                key,
+                ExtractionStatus::Failed {
+                    error: e.to_string(),
+                },
+            );
+        }
+    }
+}
+
+/// A temporary path to extract `{name}.pmtiles` to. Lazy, country and refresh jobs may
+/// extract the same archive concurrently, so each run writes its own temporary file.
+fn temp_archive_path(temp_dir: &Path, name: &str) -> PathBuf {
+    temp_dir.join(format!(
+        "{}.{}.pmtiles",
+        name,
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Region job ids are unguessable, as they are the only credential needed to fetch an archive.
+fn new_region_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A pinned build matches on its key, with or without the `.pmtiles` extension
+/// (e.g. `20251018`), or on its full `uploaded` date (e.g. `2025-10-18`).
+fn is_pinned_build(build: &serde_json::Value, pinned: &str) -> bool {
+    let key = build.get("key").and_then(|v| v.as_str()).unwrap_or("");
+    let uploaded = build.get("uploaded").and_then(|v| v.as_str()).unwrap_or("");
+    let uploaded_date = uploaded.split(['T', ' ']).next().unwrap_or("");
+
+    key == pinned
+        || key.trim_end_matches(".pmtiles") == pinned
+        || uploaded == pinned
+        || uploaded_date == pinned
+}
+
+/// The pinned build, or the latest upload when several builds share the pinned date.
+fn pinned_build<'a>(
+    builds: &'a [serde_json::Value],
+    pinned: &str,
+) -> Option<&'a serde_json::Value> {
+    latest_build(builds.iter().filter(|build| is_pinned_build(build, pinned)))
+}
+
+fn latest_build<'a>(
+    builds: impl Iterator<Item = &'a serde_json::Value>,
+) -> Option<&'a serde_json::Value> {
+    builds.max_by_key(|build| build.get("uploaded").and_then(|v| v.as_str()).unwrap_or(""))
+}
+
+/// A GeoJSON MultiPolygon of the locality bounding boxes. Unlike a single bounding box of
+/// them all, it does not span the oceans between overseas territories or across the antimeridian.
+fn localities_region(localities: &[Locality]) -> Option<serde_json::Value> {
+    let polygons: Vec<serde_json::Value> = localities
+        .iter()
+        .filter(|locality| {
+            locality.min_longitude < locality.max_longitude
+                && locality.min_latitude < locality.max_latitude
+        })
+        .map(|locality| {
+            serde_json::json!([[
+                [locality.min_longitude, locality.min_latitude],
+                [locality.max_longitude, locality.min_latitude],
+                [locality.max_longitude, locality.max_latitude],
+                [locality.min_longitude, locality.max_latitude],
+                [locality.min_longitude, locality.min_latitude]
+            ]])
+        })
+        .collect();
+
+    if polygons.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::json!({
+        "type": "MultiPolygon",
+        "coordinates": polygons
+    }))
+}
+
+/// The `[min_lon, min_lat, max_lon, max_lat]` bounds of every position in a GeoJSON value.
+fn geojson_bounds(value: &serde_json::Value) -> Option<[f64; 4]> {
+    fn visit(value: &serde_json::Value, bounds: &mut Option<[f64; 4]>) {
+        match value {
+            serde_json::Value::Array(items) => {
+                if let (Some(lon), Some(lat)) = (
+                    items.first().and_then(|v| v.as_f64()),
+                    items.get(1).and_then(|v| v.as_f64()),
+                ) {
+                    let b = bounds.get_or_insert([lon, lat, lon, lat]);
+                    *b = [b[0].min(lon), b[1].min(lat), b[2].max(lon), b[3].max(lat)];
+                } else {
+                    items.iter().for_each(|item| visit(item, bounds));
+                }
+            }
+            serde_json::Value::Object(object) => {
+                for key in ["coordinates", "geometry", "geometries", "features"] {
+                    if let Some(child) = object.get(key) {
+                        visit(child, bounds);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut bounds = None;
+    visit(value, &mut bounds);
+    bounds
+}
+
+/// The bounds of a custom region, given as exactly one of a bbox or a GeoJSON geometry,
+/// with their area. Rejects bounds outside WGS84, empty ones and areas above `max_area_km2`.
+fn region_bounds(
+    bbox: Option<[f64; 4]>,
+    geometry: Option<&serde_json::Value>,
+    max_area_km2: f64,
+) -> Result<([f64; 4], f64), ExtractionError> {
+    let bounds = match (bbox, geometry) {
+        (Some(bbox), None) => bbox,
+        (None, Some(geometry)) => geojson_bounds(geometry).ok_or_else(|| {
+            ExtractionError::InvalidRegion("GeoJSON has no coordinates".to_string())
+        })?,
+        _ => {
+            return Err(ExtractionError::InvalidRegion(
+                "Exactly one of bbox or geometry is required".to_string(),
+            ))
+        }
+    };
+
+    let [min_longitude, min_latitude, max_longitude, max_latitude] = bounds;
+    if !(-180.0..=180.0).contains(&min_longitude)
+        || !(-180.0..=180.0).contains(&max_longitude)
+        || !(-90.0..=90.0).contains(&min_latitude)
+        || !(-90.0..=90.0).contains(&max_latitude)
+        || min_longitude >= max_longitude
+        || min_latitude >= max_latitude
+    {
+        return Err(ExtractionError::InvalidRegion(
+            "Bounds are out of range or empty".to_string(),
+        ));
+    }
+
+    let area = bbox_area_km2(bounds);
+    if area > max_area_km2 {
+        return Err(ExtractionError::InvalidRegion(format!(
+            "Region covers {:.0} km², the maximum is {:.0} km²",
+            area, max_area_km2
+        )));
+    }
+
+    Ok((bounds, area))
+}
+
+/// Approximate area of a bbox, accurate enough to cap region sizes.
+fn bbox_area_km2([min_longitude, min_latitude, max_longitude, max_latitude]: [f64; 4]) -> f64 {
+    let mid_latitude = ((min_latitude + max_latitude) / 2.0).to_radians();
+    let width = (max_longitude - min_longitude) * 111.32 * mid_latitude.cos();
+    let height = (max_latitude - min_latitude) * 110.57;
+    width * height
+}
+
+/// The locality id of an archive, layout or build record in a country directory,
+/// when that locality is no longer in the database.
+fn orphaned_archive_id(file_name: &str, known_ids: &HashSet<i64>) -> Option<i64> {
+    [".pmtiles", ".layout.json", ".build.json"]
+        .iter()
+        .find_map(|suffix| file_name.strip_suffix(suffix))
+        .and_then(|id| id.parse::<i64>().ok())
+        .filter(|id| !known_ids.contains(id))
+}
+
+/// Whether a locality archive exists, either as a plain file or as a tile store layout.
+fn archive_exists(archive_path: &Path) -> bool {
+    archive_path.exists() || StorageService::layout_path(archive_path).exists()
+}
+
+fn versions_dir(country_dir: &Path, id: i64) -> PathBuf {
+    country_dir.join(".versions").join(id.to_string())
+}
+
+fn record_path(dir: &Path, name: impl std::fmt::Display) -> PathBuf {
+    dir.join(format!("{}.build.json", name))
+}
+
+fn read_record(dir: &Path, name: impl std::fmt::Display) -> Option<ArchiveRecord> {
+    std::fs::read_to_string(record_path(dir, name))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+}
+
+/// An archive is stale when its build record is missing or unreadable, names another
+/// planet build or extraction profile, or is older than `max_age` seconds.
+fn is_archive_stale(
+    dir: &Path,
+    name: impl std::fmt::Display,
+    planet: &PlanetSource,
+    profile: &ExtractionProfile,
+    max_age: Option<u64>,
+) -> bool {
+    is_record_stale(
+        read_record(dir, name).as_ref(),
+        planet,
+        profile,
+        max_age,
+        unix_timestamp(),
+    )
+}
+
+fn is_record_stale(
+    record: Option<&ArchiveRecord>,
+    planet: &PlanetSource,
+    profile: &ExtractionProfile,
+    max_age: Option<u64>,
+    now: u64,
+) -> bool {
+    let record = match record {
+        Some(record) => record,
+        None => return true,
+    };
+
+    if record.build != planet.build {
+        return true;
+    }
+
+    if record.profile != *profile {
+        return true;
+    }
+
+    match max_age {
+        Some(max_age) => now.saturating_sub(record.extracted_at) > max_age,
+        None => false,
+    }
+}
+
+/// The maximum age of an archive in seconds, saturating for huge `ARCHIVE_MAX_AGE_DAYS`.
+fn archive_max_age(days: Option<u64>) -> Option<u64> {
+    days.map(|days| days.saturating_mul(24 * 60 * 60))
+}
+
+/// Identifies a local planet file by its size and modification time, which is far
+/// cheaper than hashing a planet-sized archive.
+fn local_build_fingerprint(path: &Path) -> Result<String, ExtractionError> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Ok(format!("local-{}-{}", metadata.len(), modified))
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lazy_jobs_are_deduplicated() {
+        let mut jobs = HashMap::new();
+        let key = ("FR".to_string(), 101);
+
+        assert!(claim_lazy_job(&mut jobs, &key));
+        assert!(!claim_lazy_job(&mut jobs, &key));
+        assert!(claim_lazy_job(&mut jobs, &("FR".to_string(), 102)));
+        assert!(claim_lazy_job(&mut jobs, &("DE".to_string(), 101)));
+        assert_eq!(jobs.get(&key), Some(&ExtractionStatus::Pending));
+    }
+
+    #[test]
+    fn finished_lazy_jobs_are_forgotten_and_failed_ones_retried() {
+        let mut jobs = HashMap::new();
+        let key = ("FR".to_string(), 101);
+
+        claim_lazy_job(&mut jobs, &key);
+        finish_lazy_job(
+            &mut jobs,
+            key.clone(),
+            Err(ExtractionError::ExtractionFailed("boom".to_string())),
+        );
+        assert!(matches!(
+            jobs.get(&key),
+            Some(ExtractionStatus::Failed { error }) if error.contains("boom")
+        ));
+
+        assert!(claim_lazy_job(&mut jobs, &key));
+        finish_lazy_job(&mut jobs, key.clone(), Ok(()));
+        assert!(!jobs.contains_key(&key));
+    }
+
+    #[test]
+    fn jobs_are_paused_resumed_and_cancelled() {
+        let (mut handle, _control_rx) = JobHandle::new("FR", 3, 1);
+
+        let job = handle
+            .transition(JobState::Running, JobControl::Pause, JobState::Paused)
+            .unwrap();
+        assert_eq!(job.state, JobState::Paused);
+        assert_eq!(*handle.control.borrow(), JobControl::Pause);
+        assert!(matches!(
+            handle.transition(JobState::Running, JobControl::Pause, JobState::Paused),
+            Err(ExtractionError::InvalidJobState(_))
+        ));
+
+        handle
+            .transition(JobState::Paused, JobControl::Run, JobState::Running)
+            .unwrap();
+        assert_eq!(*handle.control.borrow(), JobControl::Run);
+
+        handle.cancel().unwrap();
+        handle.finish();
+        assert_eq!(handle.job.state, JobState::Cancelled);
+        assert!(handle.job.finished_at.is_some());
+        assert!(matches!(
+            handle.cancel(),
+            Err(ExtractionError::InvalidJobState(_))
+        ));
+    }
+
+    #[test]
+    fn finished_jobs_report_failed_localities() {
+        let (mut handle, _control_rx) = JobHandle::new("FR", 3, 1);
+        handle.record_progress(101, true);
+        handle.record_progress(102, false);
+        handle.finish();
+
+        assert_eq!(handle.job.completed, 2);
+        assert_eq!(handle.job.failed, vec![102]);
+        assert_eq!(handle.job.state, JobState::Failed);
+
+        let (mut handle, _control_rx) = JobHandle::new("DE", 1, 0);
+        handle.record_progress(201, true);
+        handle.finish();
+        assert_eq!(handle.job.state, JobState::Completed);
+    }
+
+    #[tokio::test]
+    async fn paused_tasks_wait_until_resumed_or_cancelled() {
+        let (mut handle, mut control_rx) = JobHandle::new("FR", 1, 0);
+        assert!(wait_until_runnable(&mut control_rx).await);
+
+        handle
+            .transition(JobState::Running, JobControl::Pause, JobState::Paused)
+            .unwrap();
+        let waiter = tokio::spawn(async move { wait_until_runnable(&mut control_rx).await });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        handle
+            .transition(JobState::Paused, JobControl::Run, JobState::Running)
+            .unwrap();
+        assert!(waiter.await.unwrap());
+
+        let (mut handle, mut control_rx) = JobHandle::new("DE", 1, 0);
+        handle.cancel().unwrap();
+        assert!(!wait_until_runnable(&mut control_rx).await);
+    }
+
+    fn planet(build: &str) -> PlanetSource {
+        PlanetSource {
+            url: format!("https://build.protomaps.com/{}.pmtiles", build),
+            build: build.to_string(),
+        }
+    }
+
+    fn record(build: &str, extracted_at: u64) -> ArchiveRecord {
+        ArchiveRecord {
+            build: build.to_string(),
+            extracted_at,
+            profile: ExtractionProfile::default(),
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn archives_of_another_build_or_profile_are_stale() {
+        let profile = ExtractionProfile::default();
+        let current = record("20251018", 1_000);
+
+        assert!(is_record_stale(
+            None,
+            &planet("20251018"),
+            &profile,
+            None,
+            1_000
+        ));
+        assert!(!is_record_stale(
+            Some(&current),
+            &planet("20251018"),
+            &profile,
+            None,
+            1_000
+        ));
+        assert!(is_record_stale(
+            Some(&current),
+            &planet("20251025"),
+            &profile,
+            None,
+            1_000
+        ));
+
+        let zoomed = ExtractionProfile {
+            max_zoom: Some(12),
+            ..ExtractionProfile::default()
+        };
+        assert!(is_record_stale(
+            Some(&current),
+            &planet("20251018"),
+            &zoomed,
+            None,
+            1_000
+        ));
+    }
+
+    #[test]
+    fn archives_older_than_the_max_age_are_stale() {
+        let profile = ExtractionProfile::default();
+        let planet = planet("20251018");
+        let max_age = archive_max_age(Some(1));
+        assert_eq!(max_age, Some(86_400));
+
+        let record = record("20251018", 1_000);
+        assert!(!is_record_stale(
+            Some(&record),
+            &planet,
+            &profile,
+            max_age,
+            87_400
+        ));
+        assert!(is_record_stale(
+            Some(&record),
+            &planet,
+            &profile,
+            max_age,
+            87_401
+        ));
+        // Records from the future, after a clock change, are not stale
+        assert!(!is_record_stale(
+            Some(&record),
+            &planet,
+            &profile,
+            max_age,
+            0
+        ));
+    }
+
+    #[test]
+    fn huge_max_ages_saturate() {
+        assert_eq!(archive_max_age(Some(u64::MAX)), Some(u64::MAX));
+        assert_eq!(archive_max_age(None), None);
+
+        let record = record("20251018", 0);
+        assert!(!is_record_stale(
+            Some(&record),
+            &planet("20251018"),
+            &ExtractionProfile::default(),
+            archive_max_age(Some(u64::MAX)),
+            u64::MAX
+        ));
+    }
+
+    #[test]
+    fn pinned_builds_match_exactly() {
+        let builds = vec![
+            serde_json::json!({"key": "20251018.pmtiles", "uploaded": "2025-10-18T04:00:00Z"}),
+            serde_json::json!({"key": "20251018b.pmtiles", "uploaded": "2025-10-18T16:00:00Z"}),
+            serde_json::json!({"key": "20251025.pmtiles", "uploaded": "2025-10-25T04:00:00Z"}),
+        ];
+        let key = |pinned: &str| {
+            pinned_build(&builds, pinned)
+                .and_then(|build| build.get("key"))
+                .and_then(|key| key.as_str())
+                .map(str::to_string)
+        };
+
+        assert_eq!(key("20251018").as_deref(), Some("20251018.pmtiles"));
+        assert_eq!(key("20251025.pmtiles").as_deref(), Some("20251025.pmtiles"));
+        // Builds uploaded on the pinned date resolve to the latest of them
+        assert_eq!(key("2025-10-18").as_deref(), Some("20251018b.pmtiles"));
+        // Partial keys and dates match nothing rather than an arbitrary build
+        assert_eq!(key("2025-10"), None);
+        assert_eq!(key("2025"), None);
+        assert_eq!(key("202510"), None);
+    }
+
+    fn locality(
+        id: i64,
+        [min_longitude, min_latitude, max_longitude, max_latitude]: [f64; 4],
+    ) -> Locality {
+        Locality {
+            id,
+            name: format!("Locality {}", id),
+            country: "FR".to_string(),
+            placetype: "locality".to_string(),
+            latitude: (min_latitude + max_latitude) / 2.0,
+            longitude: (min_longitude + max_longitude) / 2.0,
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        }
+    }
+
+    #[test]
+    fn country_regions_cover_each_locality_bbox() {
+        let localities = vec![
+            locality(1, [2.2, 48.8, 2.5, 48.9]),
+            // Overseas territories stay separate polygons rather than one bbox across the ocean
+            locality(2, [55.2, -21.4, 55.8, -20.8]),
+            // Points without an extent are skipped
+            locality(3, [2.3, 48.85, 2.3, 48.85]),
+        ];
+
+        let region = localities_region(&localities).unwrap();
+        assert_eq!(region["type"], "MultiPolygon");
+
+        let polygons = region["coordinates"].as_array().unwrap();
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(
+            polygons[0],
+            serde_json::json!([[
+                [2.2, 48.8],
+                [2.5, 48.8],
+                [2.5, 48.9],
+                [2.2, 48.9],
+                [2.2, 48.8]
+            ]])
+        );
+        assert_eq!(geojson_bounds(&region), Some([2.2, -21.4, 55.8, 48.9]));
+
+        assert!(localities_region(&localities[2..]).is_none());
+        assert!(localities_region(&[]).is_none());
+    }
+
+    #[test]
+    fn region_bounds_come_from_a_bbox_or_geometry() {
+        let bbox = [2.2, 48.8, 2.5, 48.9];
+        let (bounds, area) = region_bounds(Some(bbox), None, 1_000.0).unwrap();
+        assert_eq!(bounds, bbox);
+        // About 24 km by 11 km around Paris
+        assert!((200.0..300.0).contains(&area), "{}", area);
+
+        let feature = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": {"name": "ignored", "bbox": [0, 0]},
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[2.2, 48.8], [2.5, 48.85], [2.3, 48.9], [2.2, 48.8]]]
+                }
+            }]
+        });
+        let (bounds, _) = region_bounds(None, Some(&feature), 1_000.0).unwrap();
+        assert_eq!(bounds, bbox);
+    }
+
+    #[test]
+    fn invalid_or_oversized_regions_are_rejected() {
+        let geometry = serde_json::json!({"type": "Polygon", "coordinates": []});
+        let cases = [
+            (None, None),
+            (Some([2.2, 48.8, 2.5, 48.9]), Some(&geometry)),
+            (None, Some(&geometry)),
+            (Some([-181.0, 48.8, 2.5, 48.9]), None),
+            (Some([2.2, 48.8, 2.5, 91.0]), None),
+            (Some([2.5, 48.8, 2.2, 48.9]), None),
+            (Some([2.2, 48.8, 2.5, 48.8]), None),
+            // About 12,300 km² at the equator
+            (Some([0.0, 0.0, 1.0, 1.0]), None),
+        ];
+
+        for (bbox, geometry) in cases {
+            assert!(
+                matches!(
+                    region_bounds(bbox, geometry, 1_000.0),
+                    Err(ExtractionError::InvalidRegion(_))
+                ),
+                "{:?}",
+                bbox
+            );
+        }
+    }
+
+    #[test]
+    fn bbox_areas_shrink_towards_the_poles() {
+        let equator = bbox_area_km2([0.0, 0.0, 1.0, 1.0]);
+        assert!((12_000.0..12_500.0).contains(&equator), "{}", equator);
+
+        let north = bbox_area_km2([0.0, 59.0, 1.0, 60.0]);
+        assert!(
+            north < equator * 0.55 && north > equator * 0.45,
+            "{}",
+            north
+        );
+    }
+
+    #[test]
+    fn archives_of_unknown_localities_are_orphans() {
+        let known_ids: HashSet<i64> = [101, 102].into_iter().collect();
+
+        for file_name in ["103.pmtiles", "103.layout.json", "103.build.json"] {
+            assert_eq!(orphaned_archive_id(file_name, &known_ids), Some(103));
+        }
+        for file_name in [
+            "101.pmtiles",
+            "102.layout.json",
+            "102.build.json",
+            ".versions",
+            ".tmp",
+            "103.pmtiles.tmp",
+            "103.1.pmtiles",
+            "manifest.json",
+            "abc.pmtiles",
+        ] {
+            assert_eq!(
+                orphaned_archive_id(file_name, &known_ids),
+                None,
+                "{}",
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn temp_archive_paths_are_unique_per_run() {
+        let temp_dir = Path::new("/assets/localities/FR/.tmp");
+        let first = temp_archive_path(temp_dir, "101");
+        let second = temp_archive_path(temp_dir, "101");
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(temp_dir));
+        assert_eq!(first.extension().unwrap(), "pmtiles");
     }
 }
@@ -1,4 +1,8 @@
+pub mod budget;
+pub mod bundle;
 pub mod country;
 pub mod database;
 pub mod extraction;
+pub mod signing;
+pub mod storage;
 pub mod tor;
@@ -0,0 +1,119 @@
+use crate::utils::file::write_private_file;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::RngCore;
+use std::path::Path;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("Invalid signing key: {0}")]
+    InvalidKey(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Signs manifests and archive hashes with the Ed25519 key of this instance, so clients
+/// fetching over Tor can verify where the data came from.
+pub struct SigningService {
+    signing_key: SigningKey,
+}
+
+impl SigningService {
+    /// Loads the signing key from `key_path`, generating and persisting a new one if missing.
+    pub fn new(key_path: &Path) -> Result<Self, SigningError> {
+        let signing_key = if key_path.exists() {
+            let bytes: [u8; 32] = std::fs::read(key_path)?.try_into().map_err(|_| {
+                SigningError::InvalidKey(format!("{} is not a 32-byte key", key_path.display()))
+            })?;
+            SigningKey::from_bytes(&bytes)
+        } else {
+            let mut bytes = [0u8; 32];
+            rand::rngs::OsRng.fill_bytes(&mut bytes);
+
+            if let Some(parent) = key_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            write_private_file(key_path, &bytes)?;
+
+            info!("Generated signing key: {}", key_path.display());
+            SigningKey::from_bytes(&bytes)
+        };
+
+        Ok(Self { signing_key })
+    }
+
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Returns the base64 detached signature of `message`.
+    pub fn sign(&self, message: &[u8]) -> String {
+        STANDARD.encode(self.signing_key.sign(message).to_bytes())
+    }
+
+    /// Signs the hash of a locality archive together with the locality it belongs to, so a
+    /// signature cannot be replayed for another archive with the same contents.
+    pub fn sign_archive(&self, country_code: &str, id: i64, sha256: &str) -> String {
+        self.sign(archive_message(country_code, id, sha256).as_bytes())
+    }
+}
+
+/// The signed message of an archive, documented by `GET /pubkey`.
+pub const ARCHIVE_MESSAGE_FORMAT: &str = "localitysrv-archive-v1\n{country_code}\n{id}\n{sha256}";
+
+fn archive_message(country_code: &str, id: i64, sha256: &str) -> String {
+    format!(
+        "localitysrv-archive-v1\n{}\n{}\n{}",
+        country_code, id, sha256
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn archive_messages_follow_the_documented_format() {
+        let expected = ARCHIVE_MESSAGE_FORMAT
+            .replace("{country_code}", "FR")
+            .replace("{id}", "101751119")
+            .replace("{sha256}", SHA256);
+
+        assert_eq!(archive_message("FR", 101751119, SHA256), expected);
+        assert_eq!(
+            archive_message("FR", 101751119, SHA256),
+            format!("localitysrv-archive-v1\nFR\n101751119\n{}", SHA256)
+        );
+    }
+
+    #[test]
+    fn archive_signatures_verify_against_the_public_key() {
+        let service = SigningService {
+            signing_key: SigningKey::from_bytes(&[7; 32]),
+        };
+        let public_key: [u8; 32] = STANDARD
+            .decode(service.public_key())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let verifying_key = VerifyingKey::from_bytes(&public_key).unwrap();
+
+        let signature: [u8; 64] = STANDARD
+            .decode(service.sign_archive("FR", 101751119, SHA256))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = Signature::from_bytes(&signature);
+
+        let message = archive_message("FR", 101751119, SHA256);
+        assert!(verifying_key.verify(message.as_bytes(), &signature).is_ok());
+        // The signature is bound to the locality, not only to the contents
+        let other = archive_message("FR", 101751120, SHA256);
+        assert!(verifying_key.verify(other.as_bytes(), &signature).is_err());
+    }
+}
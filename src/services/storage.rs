@@ -0,0 +1,1241 @@
+use crate::models::storage::{ArchiveLayout, BlobRef, StorageReport};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::RwLock;
+use tokio_util::bytes::Bytes;
+use tokio_util::io::ReaderStream;
+
+const HEADER_LENGTH: usize = 127;
+const MAX_DIRECTORY_DEPTH: usize = 4;
+const MAX_ROOT_DIRECTORY_LENGTH: usize = 16384 - HEADER_LENGTH;
+const LEAF_DIRECTORY_ENTRIES: usize = 4096;
+/// Bounds the tiles addressed by run-length encoded entries of an untrusted archive
+const MAX_ADDRESSED_TILES: u64 = 1 << 24;
+const PATCH_FORMAT: &str = "localitysrv-delta-1";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Unsupported archive: {0}")]
+    UnsupportedArchive(String),
+    #[error("Invalid layout: {0}")]
+    InvalidLayout(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Tokio join error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+}
+
+/// Content-addressed store for the tiles of locality archives. Identical tiles shared by
+/// overlapping localities are stored once, and archives are served from their layout.
+pub struct StorageService {
+    tiles_dir: PathBuf,
+    localities_dir: PathBuf,
+    quarantine_dir: PathBuf,
+    /// Held for reading while blobs are stored and their layout is not written yet
+    gc_lock: RwLock<()>,
+}
+
+struct Header {
+    root_dir_offset: u64,
+    root_dir_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_dirs_offset: u64,
+    leaf_dirs_length: u64,
+    tile_data_offset: u64,
+    tile_data_length: u64,
+    internal_compression: u8,
+}
+
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+impl StorageService {
+    pub fn new(tiles_dir: PathBuf, localities_dir: PathBuf, quarantine_dir: PathBuf) -> Self {
+        Self {
+            tiles_dir,
+            localities_dir,
+            quarantine_dir,
+            gc_lock: RwLock::new(()),
+        }
+    }
+
+    /// The layout of `{id}.pmtiles` is stored next to it as `{id}.layout.json`.
+    pub fn layout_path(archive_path: &Path) -> PathBuf {
+        archive_path.with_extension("layout.json")
+    }
+
+    /// Moves the tiles of an archive into the store, then replaces the archive by its layout.
+    pub async fn ingest_archive(&self, archive_path: &Path) -> Result<ArchiveLayout, StorageError> {
+        let _guard = self.gc_lock.read().await;
+        let tiles_dir = self.tiles_dir.clone();
+        let archive_path = archive_path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || ingest_archive(&tiles_dir, &archive_path)).await?
+    }
+
+    /// Removes the blobs referenced by no layout, of current archives, previous versions or
+    /// quarantined archives. Returns the number of blobs and bytes removed.
+    pub async fn collect_garbage(&self) -> Result<(usize, u64), StorageError> {
+        let _guard = self.gc_lock.write().await;
+        let tiles_dir = self.tiles_dir.clone();
+        let layout_dirs = vec![self.localities_dir.clone(), self.quarantine_dir.clone()];
+
+        tokio::task::spawn_blocking(move || collect_garbage(&tiles_dir, &layout_dirs)).await?
+    }
+
+    pub async fn read_layout(&self, archive_path: &Path) -> Result<ArchiveLayout, StorageError> {
+        let content = tokio::fs::read_to_string(Self::layout_path(archive_path)).await?;
+        serde_json::from_str(&content).map_err(|e| StorageError::InvalidLayout(e.to_string()))
+    }
+
+    pub fn blob_paths(&self, layout: &ArchiveLayout) -> Vec<PathBuf> {
+        layout
+            .segments
+            .iter()
+            .map(|segment| blob_path(&self.tiles_dir, &segment.hash))
+            .collect()
+    }
+
+    /// Whether an archive can be served: a plain file must have a PMTiles v3 header and
+    /// hold all of its sections, a layout must parse and have its header blob in the store.
+    pub async fn is_valid_archive(&self, archive_path: &Path) -> bool {
+        if let Ok(mut file) = tokio::fs::File::open(archive_path).await {
+            let mut data = vec![0u8; HEADER_LENGTH];
+            if file.read_exact(&mut data).await.is_err() {
+                return false;
+            }
+
+            let (header, file_length) = match (parse_header(&data), file.metadata().await) {
+                (Ok(header), Ok(metadata)) => (header, metadata.len()),
+                _ => return false,
+            };
+
+            return sections_within(&header, file_length);
+        }
+
+        match self.read_layout(archive_path).await {
+            Ok(layout) => layout
+                .segments
+                .first()
+                .is_some_and(|segment| blob_path(&self.tiles_dir, &segment.hash).exists()),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the size and SHA-256 of an archive, plain or virtual.
+    pub async fn archive_digest(&self, archive_path: &Path) -> Result<(u64, String), StorageError> {
+        let paths = if archive_path.exists() {
+            vec![archive_path.to_path_buf()]
+        } else {
+            let layout = self.read_layout(archive_path).await?;
+            self.blob_paths(&layout)
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+
+            for path in paths {
+                let mut file = std::fs::File::open(path)?;
+                size += std::io::copy(&mut file, &mut hasher)?;
+            }
+
+            Ok((size, format!("{:x}", hasher.finalize())))
+        })
+        .await?
+    }
+
+    /// Reads a whole archive, plain or virtual.
+    pub async fn read_archive(&self, archive_path: &Path) -> Result<Vec<u8>, StorageError> {
+        match tokio::fs::read(archive_path).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let layout = self.read_layout(archive_path).await?;
+                match layout.length() {
+                    0 => Ok(Vec::new()),
+                    length => self.read_range(&layout, 0, length - 1).await,
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Merges the fields of a JSON object into the metadata of a plain archive.
+    pub async fn embed_metadata(
+        &self,
+        archive_path: &Path,
+        fields: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        let archive_path = archive_path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || embed_metadata(&archive_path, &fields)).await?
+    }
+
+    /// Builds a patch turning the `base` archive into the `target` one.
+    pub async fn build_patch(
+        &self,
+        base: Vec<u8>,
+        target: Vec<u8>,
+        from: String,
+        to: String,
+    ) -> Result<Vec<u8>, StorageError> {
+        tokio::task::spawn_blocking(move || build_patch(&base, &target, &from, &to)).await?
+    }
+
+    /// Reads the inclusive byte range `start..=end` of a virtual archive.
+    pub async fn read_range(
+        &self,
+        layout: &ArchiveLayout,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut buffer = Vec::with_capacity((end - start + 1) as usize);
+
+        for (path, offset, length) in range_segments(&self.tiles_dir, layout, start, end) {
+            let mut file = tokio::fs::File::open(path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let mut chunk = vec![0u8; length as usize];
+            file.read_exact(&mut chunk).await?;
+            buffer.extend_from_slice(&chunk);
+        }
+
+        Ok(buffer)
+    }
+
+    /// Streams the inclusive byte range `start..=end` of a virtual archive, one blob at a time.
+    pub fn stream_range(
+        &self,
+        layout: &ArchiveLayout,
+        start: u64,
+        end: u64,
+    ) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+        futures::stream::iter(range_segments(&self.tiles_dir, layout, start, end))
+            .then(|(path, offset, length)| async move {
+                let mut file = tokio::fs::File::open(path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                Ok::<_, std::io::Error>(ReaderStream::new(file.take(length)))
+            })
+            .try_flatten()
+    }
+
+    /// Compares the size of all virtual archives with the size of the store.
+    pub async fn report(&self) -> Result<StorageReport, StorageError> {
+        let tiles_dir = self.tiles_dir.clone();
+        let localities_dir = self.localities_dir.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut archives = 0;
+            let mut logical_bytes = 0;
+
+            for country_dir in read_dir_paths(&localities_dir)? {
+                for path in read_dir_paths(&country_dir)? {
+                    let is_layout = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.ends_with(".layout.json"));
+
+                    if is_layout {
+                        let content = std::fs::read_to_string(&path)?;
+                        let layout: ArchiveLayout = serde_json::from_str(&content)
+                            .map_err(|e| StorageError::InvalidLayout(e.to_string()))?;
+                        archives += 1;
+                        logical_bytes += layout.length();
+                    }
+                }
+            }
+
+            let mut blobs = 0;
+            let mut stored_bytes = 0;
+
+            for prefix_dir in read_dir_paths(&tiles_dir)? {
+                for path in read_dir_paths(&prefix_dir)? {
+                    // Blobs are named by their hash, anything else is a temporary file
+                    if path.extension().is_none() {
+                        blobs += 1;
+                        stored_bytes += std::fs::metadata(&path)?.len();
+                    }
+                }
+            }
+
+            Ok(StorageReport {
+                archives,
+                blobs,
+                logical_bytes,
+                stored_bytes,
+                saved_bytes: logical_bytes.saturating_sub(stored_bytes),
+            })
+        })
+        .await?
+    }
+}
+
+fn ingest_archive(tiles_dir: &Path, archive_path: &Path) -> Result<ArchiveLayout, StorageError> {
+    let data = std::fs::read(archive_path)?;
+    let header = parse_header(&data)?;
+
+    // Only archives whose tile data is the last section can be split into a head and tiles
+    let tile_data_end = header.tile_data_offset.checked_add(header.tile_data_length);
+    let other_sections_end = [
+        header.root_dir_offset.checked_add(header.root_dir_length),
+        header.metadata_offset.checked_add(header.metadata_length),
+        header.leaf_dirs_offset.checked_add(header.leaf_dirs_length),
+    ];
+    if tile_data_end != Some(data.len() as u64)
+        || !other_sections_end
+            .iter()
+            .all(|end| matches!(end, Some(end) if *end <= header.tile_data_offset))
+    {
+        return Err(StorageError::UnsupportedArchive(
+            "Tile data is not the last section".to_string(),
+        ));
+    }
+
+    let mut entries = Vec::new();
+    collect_tiles(
+        &data,
+        &header,
+        header.root_dir_offset,
+        header.root_dir_length,
+        0,
+        &mut entries,
+    )?;
+    let mut tiles: Vec<(u64, u64)> = entries
+        .iter()
+        .map(|entry| (entry.offset, entry.length))
+        .collect();
+    tiles.sort_unstable();
+    tiles.dedup();
+
+    let head = section(&data, 0, header.tile_data_offset, "Archive head")?;
+    let tile_data = section(
+        &data,
+        header.tile_data_offset,
+        header.tile_data_length,
+        "Tile data",
+    )?;
+    let mut segments = vec![store_blob(tiles_dir, head)?];
+    let mut expected_offset = 0;
+
+    for (offset, length) in tiles {
+        if offset != expected_offset {
+            return Err(StorageError::UnsupportedArchive(
+                "Tile data is not contiguous".to_string(),
+            ));
+        }
+
+        segments.push(store_blob(
+            tiles_dir,
+            section(tile_data, offset, length, "Tile")?,
+        )?);
+        expected_offset = offset + length;
+    }
+
+    if expected_offset != header.tile_data_length {
+        return Err(StorageError::UnsupportedArchive(
+            "Tile data is not contiguous".to_string(),
+        ));
+    }
+
+    let layout = ArchiveLayout { segments };
+    let layout_json =
+        serde_json::to_string(&layout).map_err(|e| StorageError::InvalidLayout(e.to_string()))?;
+
+    let layout_path = StorageService::layout_path(archive_path);
+    let temp_path = layout_path.with_extension("json.tmp");
+    std::fs::write(&temp_path, layout_json)?;
+    std::fs::rename(&temp_path, &layout_path)?;
+    std::fs::remove_file(archive_path)?;
+
+    Ok(layout)
+}
+
+/// Rewrites an archive with merged metadata. Directories are copied as they are and the
+/// tile data is streamed, so large country archives are never loaded in memory.
+fn embed_metadata(archive_path: &Path, fields: &serde_json::Value) -> Result<(), StorageError> {
+    let mut file = std::fs::File::open(archive_path)?;
+    let file_length = file.metadata()?.len();
+
+    let mut header_bytes = [0u8; HEADER_LENGTH];
+    file.read_exact(&mut header_bytes)?;
+    let header = parse_header(&header_bytes)?;
+
+    let root_dir = read_section(
+        &mut file,
+        file_length,
+        header.root_dir_offset,
+        header.root_dir_length,
+    )?;
+    let metadata = read_section(
+        &mut file,
+        file_length,
+        header.metadata_offset,
+        header.metadata_length,
+    )?;
+    let leaf_dirs = read_section(
+        &mut file,
+        file_length,
+        header.leaf_dirs_offset,
+        header.leaf_dirs_length,
+    )?;
+
+    let mut metadata: serde_json::Value = if metadata.is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_slice(&decompress(&metadata, header.internal_compression)?)
+            .map_err(|e| StorageError::UnsupportedArchive(format!("Invalid metadata: {}", e)))?
+    };
+    match (metadata.as_object_mut(), fields.as_object()) {
+        (Some(metadata), Some(fields)) => {
+            for (key, value) in fields {
+                metadata.insert(key.clone(), value.clone());
+            }
+        }
+        _ => {
+            return Err(StorageError::UnsupportedArchive(
+                "Metadata is not a JSON object".to_string(),
+            ))
+        }
+    }
+    let metadata = serde_json::to_vec(&metadata)
+        .map_err(|e| StorageError::UnsupportedArchive(e.to_string()))?;
+    let metadata = compress(&metadata, header.internal_compression)?;
+
+    let root_dir_offset = HEADER_LENGTH as u64;
+    let metadata_offset = root_dir_offset + root_dir.len() as u64;
+    let leaf_dirs_offset = metadata_offset + metadata.len() as u64;
+    let tile_data_offset = leaf_dirs_offset + leaf_dirs.len() as u64;
+
+    // Only the section offsets and lengths change, counts, zooms and bounds are kept
+    let mut new_header = header_bytes;
+    for (i, value) in [
+        root_dir_offset,
+        root_dir.len() as u64,
+        metadata_offset,
+        metadata.len() as u64,
+        leaf_dirs_offset,
+        leaf_dirs.len() as u64,
+        tile_data_offset,
+        header.tile_data_length,
+    ]
+    .iter()
+    .enumerate()
+    {
+        new_header[8 + i * 8..16 + i * 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let temp_path = archive_path.with_extension("metadata.pmtiles");
+    let result = (|| -> Result<(), StorageError> {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&temp_path)?);
+        output.write_all(&new_header)?;
+        output.write_all(&root_dir)?;
+        output.write_all(&metadata)?;
+        output.write_all(&leaf_dirs)?;
+
+        section_end(
+            header.tile_data_offset,
+            header.tile_data_length,
+            file_length,
+        )?;
+        file.seek(SeekFrom::Start(header.tile_data_offset))?;
+        let copied = std::io::copy(&mut (&mut file).take(header.tile_data_length), &mut output)?;
+        if copied != header.tile_data_length {
+            return Err(StorageError::UnsupportedArchive(
+                "Tile data is truncated".to_string(),
+            ));
+        }
+
+        output.flush()?;
+        std::fs::rename(&temp_path, archive_path)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Builds a PMTiles archive of the tiles of `target` that are missing from or differ in
+/// `base`. Its metadata describes the patch: the ids of the tiles to remove from `base`,
+/// and the metadata of `target` to use once the patch is applied.
+fn build_patch(base: &[u8], target: &[u8], from: &str, to: &str) -> Result<Vec<u8>, StorageError> {
+    let base_tiles = tile_contents(base)?;
+    let target_tiles = tile_contents(target)?;
+    let target_header = parse_header(target)?;
+
+    let removed: Vec<u64> = base_tiles
+        .keys()
+        .filter(|tile_id| !target_tiles.contains_key(tile_id))
+        .copied()
+        .collect();
+
+    let changed: Vec<(u64, &[u8])> = target_tiles
+        .iter()
+        .filter(|(tile_id, bytes)| base_tiles.get(tile_id) != Some(bytes))
+        .map(|(tile_id, bytes)| (*tile_id, *bytes))
+        .collect();
+
+    let target_metadata: serde_json::Value = match section(
+        target,
+        target_header.metadata_offset,
+        target_header.metadata_length,
+        "Metadata",
+    ) {
+        Ok(bytes) => decompress(bytes, target_header.internal_compression)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default(),
+        Err(_) => serde_json::Value::Null,
+    };
+
+    let metadata = serde_json::to_vec(&serde_json::json!({
+        "delta": {
+            "format": PATCH_FORMAT,
+            "from": from,
+            "to": to,
+            "removed": removed
+        },
+        "metadata": target_metadata
+    }))
+    .map_err(|e| StorageError::UnsupportedArchive(e.to_string()))?;
+
+    Ok(write_archive(
+        &changed,
+        &metadata,
+        &target[98..HEADER_LENGTH],
+    ))
+}
+
+/// Serializes a clustered archive of tiles sorted by id, with uncompressed directories and
+/// metadata. `header_tail` holds the tile compression and type, zooms, bounds and center.
+fn write_archive(tiles: &[(u64, &[u8])], metadata: &[u8], header_tail: &[u8]) -> Vec<u8> {
+    let mut tile_data = Vec::new();
+    let mut entries = Vec::new();
+    for (tile_id, bytes) in tiles {
+        entries.push(Entry {
+            tile_id: *tile_id,
+            offset: tile_data.len() as u64,
+            length: bytes.len() as u64,
+            run_length: 1,
+        });
+        tile_data.extend_from_slice(bytes);
+    }
+
+    let (root_dir, leaf_dirs) = build_directories(&entries);
+
+    let root_dir_offset = HEADER_LENGTH as u64;
+    let metadata_offset = root_dir_offset + root_dir.len() as u64;
+    let leaf_dirs_offset = metadata_offset + metadata.len() as u64;
+    let tile_data_offset = leaf_dirs_offset + leaf_dirs.len() as u64;
+    let tile_count = entries.len() as u64;
+
+    let mut archive = Vec::with_capacity(tile_data_offset as usize + tile_data.len());
+    archive.extend_from_slice(b"PMTiles");
+    archive.push(3);
+    for value in [
+        root_dir_offset,
+        root_dir.len() as u64,
+        metadata_offset,
+        metadata.len() as u64,
+        leaf_dirs_offset,
+        leaf_dirs.len() as u64,
+        tile_data_offset,
+        tile_data.len() as u64,
+        tile_count,
+        tile_count,
+        tile_count,
+    ] {
+        archive.extend_from_slice(&value.to_le_bytes());
+    }
+    // Clustered, uncompressed directories
+    archive.push(1);
+    archive.push(1);
+    archive.extend_from_slice(header_tail);
+
+    archive.extend_from_slice(&root_dir);
+    archive.extend_from_slice(metadata);
+    archive.extend_from_slice(&leaf_dirs);
+    archive.extend_from_slice(&tile_data);
+
+    archive
+}
+
+/// Maps the id of every tile of an archive to its content.
+fn tile_contents(data: &[u8]) -> Result<BTreeMap<u64, &[u8]>, StorageError> {
+    let header = parse_header(data)?;
+
+    let mut entries = Vec::new();
+    collect_tiles(
+        data,
+        &header,
+        header.root_dir_offset,
+        header.root_dir_length,
+        0,
+        &mut entries,
+    )?;
+
+    let tile_data = section(
+        data,
+        header.tile_data_offset,
+        header.tile_data_length,
+        "Tile data",
+    )?;
+
+    let mut tiles = BTreeMap::new();
+    let mut addressed_tiles = 0u64;
+    for entry in entries {
+        let bytes = section(tile_data, entry.offset, entry.length, "Tile")?;
+
+        addressed_tiles = addressed_tiles.saturating_add(entry.run_length);
+        if addressed_tiles > MAX_ADDRESSED_TILES {
+            return Err(StorageError::UnsupportedArchive(
+                "Too many tiles".to_string(),
+            ));
+        }
+
+        for run in 0..entry.run_length {
+            let tile_id = entry.tile_id.checked_add(run).ok_or_else(|| {
+                StorageError::UnsupportedArchive("Tile id is out of range".to_string())
+            })?;
+            tiles.insert(tile_id, bytes);
+        }
+    }
+
+    Ok(tiles)
+}
+
+/// Serializes the directories of tile entries sorted by id, moving them to leaf directories
+/// when the root directory would not fit in the first 16 KiB of the archive.
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let root_dir = serialize_directory(entries);
+    if root_dir.len() <= MAX_ROOT_DIRECTORY_LENGTH {
+        return (root_dir, Vec::new());
+    }
+
+    let mut leaf_dirs = Vec::new();
+    let mut root_entries = Vec::new();
+    for chunk in entries.chunks(LEAF_DIRECTORY_ENTRIES) {
+        let leaf_dir = serialize_directory(chunk);
+        root_entries.push(Entry {
+            tile_id: chunk[0].tile_id,
+            offset: leaf_dirs.len() as u64,
+            length: leaf_dir.len() as u64,
+            run_length: 0,
+        });
+        leaf_dirs.extend_from_slice(&leaf_dir);
+    }
+
+    (serialize_directory(&root_entries), leaf_dirs)
+}
+
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_varint(&mut buffer, entries.len() as u64);
+
+    let mut last_tile_id = 0;
+    for entry in entries {
+        write_varint(&mut buffer, entry.tile_id - last_tile_id);
+        last_tile_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buffer, entry.run_length);
+    }
+    for entry in entries {
+        write_varint(&mut buffer, entry.length);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // An offset of 0 means the entry directly follows the previous one
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length {
+            write_varint(&mut buffer, 0);
+        } else {
+            write_varint(&mut buffer, entry.offset + 1);
+        }
+    }
+
+    buffer
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, StorageError> {
+    if data.len() < HEADER_LENGTH || &data[0..7] != b"PMTiles" || data[7] != 3 {
+        return Err(StorageError::UnsupportedArchive(
+            "Not a PMTiles v3 archive".to_string(),
+        ));
+    }
+
+    let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+    Ok(Header {
+        root_dir_offset: read_u64(8),
+        root_dir_length: read_u64(16),
+        metadata_offset: read_u64(24),
+        metadata_length: read_u64(32),
+        leaf_dirs_offset: read_u64(40),
+        leaf_dirs_length: read_u64(48),
+        tile_data_offset: read_u64(56),
+        tile_data_length: read_u64(64),
+        internal_compression: data[97],
+    })
+}
+
+/// Collects the tile entries of a directory and of its leaf directories.
+fn collect_tiles(
+    data: &[u8],
+    header: &Header,
+    offset: u64,
+    length: u64,
+    depth: usize,
+    tiles: &mut Vec<Entry>,
+) -> Result<(), StorageError> {
+    if depth > MAX_DIRECTORY_DEPTH {
+        return Err(StorageError::UnsupportedArchive(
+            "Directories are nested too deeply".to_string(),
+        ));
+    }
+
+    let directory = decompress(
+        section(data, offset, length, "Directory")?,
+        header.internal_compression,
+    )?;
+
+    for entry in parse_directory(&directory)? {
+        if entry.run_length == 0 {
+            let leaf_offset = header
+                .leaf_dirs_offset
+                .checked_add(entry.offset)
+                .ok_or_else(|| {
+                    StorageError::UnsupportedArchive("Directory is out of bounds".to_string())
+                })?;
+            collect_tiles(data, header, leaf_offset, entry.length, depth + 1, tiles)?;
+        } else {
+            tiles.push(entry);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether every section of an archive lies within its `file_length` bytes.
+fn sections_within(header: &Header, file_length: u64) -> bool {
+    [
+        (header.root_dir_offset, header.root_dir_length),
+        (header.metadata_offset, header.metadata_length),
+        (header.leaf_dirs_offset, header.leaf_dirs_length),
+        (header.tile_data_offset, header.tile_data_length),
+    ]
+    .iter()
+    .all(|(offset, length)| section_end(*offset, *length, file_length).is_ok())
+}
+
+/// The `offset..offset + length` slice of an archive, checked against untrusted offsets.
+fn section<'a>(
+    data: &'a [u8],
+    offset: u64,
+    length: u64,
+    name: &str,
+) -> Result<&'a [u8], StorageError> {
+    offset
+        .checked_add(length)
+        .and_then(|end| {
+            let start = usize::try_from(offset).ok()?;
+            let end = usize::try_from(end).ok()?;
+            data.get(start..end)
+        })
+        .ok_or_else(|| StorageError::UnsupportedArchive(format!("{} is out of bounds", name)))
+}
+
+/// The end of the section `offset..offset + length`, which must lie within `file_length`.
+fn section_end(offset: u64, length: u64, file_length: u64) -> Result<u64, StorageError> {
+    offset
+        .checked_add(length)
+        .filter(|end| *end <= file_length)
+        .ok_or_else(|| StorageError::UnsupportedArchive("Section is out of bounds".to_string()))
+}
+
+fn read_section(
+    file: &mut std::fs::File,
+    file_length: u64,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, StorageError> {
+    section_end(offset, length, file_length)?;
+
+    let mut buffer = vec![0u8; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn compress(bytes: &[u8], compression: u8) -> Result<Vec<u8>, StorageError> {
+    match compression {
+        1 => Ok(bytes.to_vec()),
+        2 => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            Ok(encoder.finish()?)
+        }
+        other => Err(StorageError::UnsupportedArchive(format!(
+            "Unsupported directory compression: {}",
+            other
+        ))),
+    }
+}
+
+fn decompress(bytes: &[u8], compression: u8) -> Result<Vec<u8>, StorageError> {
+    match compression {
+        1 => Ok(bytes.to_vec()),
+        2 => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        other => Err(StorageError::UnsupportedArchive(format!(
+            "Unsupported directory compression: {}",
+            other
+        ))),
+    }
+}
+
+fn parse_directory(buffer: &[u8]) -> Result<Vec<Entry>, StorageError> {
+    let mut position = 0;
+    let count = read_varint(buffer, &mut position)? as usize;
+
+    // Every entry takes at least one byte per field, which bounds the allocations below
+    if count > buffer.len() {
+        return Err(StorageError::UnsupportedArchive(
+            "Invalid directory entry count".to_string(),
+        ));
+    }
+
+    // Tile ids are delta-encoded
+    let mut entries = Vec::with_capacity(count);
+    let mut tile_id = 0u64;
+    for _ in 0..count {
+        tile_id = tile_id.wrapping_add(read_varint(buffer, &mut position)?);
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(buffer, &mut position)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(buffer, &mut position)?;
+    }
+    for i in 0..count {
+        let value = read_varint(buffer, &mut position)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .ok_or_else(|| {
+                    StorageError::UnsupportedArchive("Invalid directory entry offset".to_string())
+                })?
+        } else {
+            value.checked_sub(1).ok_or_else(|| {
+                StorageError::UnsupportedArchive("Invalid directory entry offset".to_string())
+            })?
+        };
+    }
+
+    Ok(entries)
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> Result<u64, StorageError> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *buffer
+            .get(*position)
+            .ok_or_else(|| StorageError::UnsupportedArchive("Truncated directory".to_string()))?;
+        *position += 1;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(StorageError::UnsupportedArchive(
+        "Invalid varint in directory".to_string(),
+    ))
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn collect_garbage(
+    tiles_dir: &Path,
+    layout_dirs: &[PathBuf],
+) -> Result<(usize, u64), StorageError> {
+    let mut referenced = HashSet::new();
+    for dir in layout_dirs {
+        collect_layout_hashes(dir, &mut referenced)?;
+    }
+
+    let mut blobs = 0;
+    let mut bytes = 0;
+    for prefix_dir in read_dir_paths(tiles_dir)? {
+        for path in read_dir_paths(&prefix_dir)? {
+            let hash = match path.file_name().and_then(|name| name.to_str()) {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            // Blobs are named by their hash, anything else is a temporary file
+            if path.extension().is_none() && !referenced.contains(hash) {
+                bytes += std::fs::metadata(&path)?.len();
+                std::fs::remove_file(&path)?;
+                blobs += 1;
+            }
+        }
+    }
+
+    Ok((blobs, bytes))
+}
+
+/// Collects the blob hashes of every layout below `dir`. An unreadable layout fails the
+/// collection, so its blobs are never removed.
+fn collect_layout_hashes(dir: &Path, hashes: &mut HashSet<String>) -> Result<(), StorageError> {
+    for path in read_dir_paths(dir)? {
+        if path.is_dir() {
+            collect_layout_hashes(&path, hashes)?;
+            continue;
+        }
+
+        let is_layout = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(".layout.json"));
+        if is_layout {
+            let content = std::fs::read_to_string(&path)?;
+            let layout: ArchiveLayout = serde_json::from_str(&content)
+                .map_err(|e| StorageError::InvalidLayout(format!("{}: {}", path.display(), e)))?;
+            hashes.extend(layout.segments.into_iter().map(|segment| segment.hash));
+        }
+    }
+
+    Ok(())
+}
+
+fn store_blob(tiles_dir: &Path, bytes: &[u8]) -> Result<BlobRef, StorageError> {
+    let hash = format!("{:x}", Sha256::digest(bytes));
+    let path = blob_path(tiles_dir, &hash);
+
+    if !path.exists() {
+        let dir = tiles_dir.join(&hash[..2]);
+        std::fs::create_dir_all(&dir)?;
+
+        // Concurrent ingests may store the same blob, so each writes its own temporary file
+        let temp_path = dir.join(format!(
+            "{}.{}.tmp",
+            hash,
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&temp_path, bytes)?;
+        std::fs::rename(&temp_path, &path)?;
+    }
+
+    Ok(BlobRef {
+        hash,
+        length: bytes.len() as u64,
+    })
+}
+
+/// The blob reads covering the inclusive byte range `start..=end` of a virtual archive,
+/// as the blob path, the offset in the blob and the length to read.
+fn range_segments(
+    tiles_dir: &Path,
+    layout: &ArchiveLayout,
+    start: u64,
+    end: u64,
+) -> Vec<(PathBuf, u64, u64)> {
+    let mut reads = Vec::new();
+    let mut segment_start = 0;
+
+    for segment in &layout.segments {
+        let segment_end = segment_start + segment.length;
+
+        if segment_end > start && segment_start <= end {
+            let from = start.saturating_sub(segment_start);
+            let to = (end + 1).min(segment_end) - segment_start;
+            reads.push((blob_path(tiles_dir, &segment.hash), from, to - from));
+        }
+
+        if segment_end > end {
+            break;
+        }
+        segment_start = segment_end;
+    }
+
+    reads
+}
+
+fn blob_path(tiles_dir: &Path, hash: &str) -> PathBuf {
+    tiles_dir.join(&hash[..2]).join(hash)
+}
+
+fn read_dir_paths(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_tail() -> Vec<u8> {
+        let mut tail = vec![0u8; HEADER_LENGTH - 98];
+        // Uncompressed MVT tiles, zooms 0 to 14
+        tail[0] = 1;
+        tail[1] = 1;
+        tail[3] = 14;
+        tail
+    }
+
+    fn archive(tiles: &BTreeMap<u64, Vec<u8>>) -> Vec<u8> {
+        let tiles: Vec<(u64, &[u8])> = tiles
+            .iter()
+            .map(|(tile_id, bytes)| (*tile_id, bytes.as_slice()))
+            .collect();
+        write_archive(&tiles, br#"{"name":"test"}"#, &header_tail())
+    }
+
+    fn owned(tiles: BTreeMap<u64, &[u8]>) -> BTreeMap<u64, Vec<u8>> {
+        tiles
+            .into_iter()
+            .map(|(tile_id, bytes)| (tile_id, bytes.to_vec()))
+            .collect()
+    }
+
+    fn set_u64(data: &mut [u8], offset: usize, value: u64) {
+        data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn written_archive_parses_back() {
+        let tiles: BTreeMap<u64, Vec<u8>> =
+            [(0, b"a".to_vec()), (5, b"bb".to_vec()), (6, b"c".to_vec())]
+                .into_iter()
+                .collect();
+        let data = archive(&tiles);
+
+        let header = parse_header(&data).unwrap();
+        assert_eq!(header.root_dir_offset, HEADER_LENGTH as u64);
+        assert_eq!(header.tile_data_length, 4);
+        assert_eq!(
+            section(
+                &data,
+                header.metadata_offset,
+                header.metadata_length,
+                "Metadata"
+            )
+            .unwrap(),
+            br#"{"name":"test"}"#
+        );
+        assert_eq!(owned(tile_contents(&data).unwrap()), tiles);
+    }
+
+    #[test]
+    fn written_archive_with_leaf_directories_parses_back() {
+        let tiles: BTreeMap<u64, Vec<u8>> = (0..20_000u64)
+            .map(|tile_id| (tile_id * 3, tile_id.to_le_bytes().to_vec()))
+            .collect();
+        let data = archive(&tiles);
+
+        let header = parse_header(&data).unwrap();
+        assert!(header.leaf_dirs_length > 0);
+        assert!(header.root_dir_length as usize <= MAX_ROOT_DIRECTORY_LENGTH);
+        assert_eq!(owned(tile_contents(&data).unwrap()), tiles);
+    }
+
+    #[test]
+    fn patch_applied_to_base_gives_target() {
+        let base: BTreeMap<u64, Vec<u8>> = [
+            (1, b"one".to_vec()),
+            (2, b"two".to_vec()),
+            (3, b"three".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+        let target: BTreeMap<u64, Vec<u8>> = [
+            (2, b"two".to_vec()),
+            (3, b"THREE".to_vec()),
+            (4, b"four".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+
+        let patch = build_patch(&archive(&base), &archive(&target), "base", "target").unwrap();
+
+        let header = parse_header(&patch).unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(
+            section(
+                &patch,
+                header.metadata_offset,
+                header.metadata_length,
+                "Metadata",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(metadata["delta"]["format"], PATCH_FORMAT);
+        assert_eq!(metadata["delta"]["to"], "target");
+        assert_eq!(metadata["metadata"]["name"], "test");
+
+        let changed = owned(tile_contents(&patch).unwrap());
+        assert_eq!(changed.keys().copied().collect::<Vec<_>>(), vec![3, 4]);
+
+        let mut patched = base.clone();
+        for tile_id in metadata["delta"]["removed"].as_array().unwrap() {
+            patched.remove(&tile_id.as_u64().unwrap());
+        }
+        patched.extend(changed);
+        assert_eq!(patched, target);
+    }
+
+    #[test]
+    fn truncated_archives_are_rejected() {
+        let tiles: BTreeMap<u64, Vec<u8>> = [(0, b"tile".to_vec())].into_iter().collect();
+        let data = archive(&tiles);
+
+        assert!(parse_header(&data[..HEADER_LENGTH - 1]).is_err());
+        assert!(tile_contents(&data[..HEADER_LENGTH + 1]).is_err());
+        assert!(tile_contents(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn overflowing_offsets_are_rejected() {
+        let tiles: BTreeMap<u64, Vec<u8>> = [(0, b"tile".to_vec())].into_iter().collect();
+        let data = archive(&tiles);
+
+        // Root directory and tile data offsets
+        for offset in [8, 56] {
+            let mut corrupted = data.clone();
+            set_u64(&mut corrupted, offset, u64::MAX - 1);
+            assert!(tile_contents(&corrupted).is_err());
+            assert!(build_patch(&data, &corrupted, "a", "b").is_err());
+        }
+
+        // Patches of an archive with unreadable metadata carry none
+        let mut corrupted = data.clone();
+        set_u64(&mut corrupted, 24, u64::MAX - 1);
+        let patch = build_patch(&data, &corrupted, "a", "b").unwrap();
+        let header = parse_header(&patch).unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(
+            section(
+                &patch,
+                header.metadata_offset,
+                header.metadata_length,
+                "Metadata",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(metadata["metadata"].is_null());
+
+        let mut corrupted = data.clone();
+        set_u64(&mut corrupted, 64, u64::MAX);
+        assert!(tile_contents(&corrupted).is_err());
+
+        assert!(section(&data, u64::MAX, 2, "Section").is_err());
+        assert!(section_end(u64::MAX, 2, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn archives_with_sections_past_their_end_are_invalid() {
+        let tiles: BTreeMap<u64, Vec<u8>> = [(0, b"tile".to_vec())].into_iter().collect();
+        let data = archive(&tiles);
+        let file_length = data.len() as u64;
+
+        assert!(sections_within(&parse_header(&data).unwrap(), file_length));
+        assert!(!sections_within(
+            &parse_header(&data).unwrap(),
+            file_length - 1
+        ));
+
+        // Offsets that wrap around once their length is added
+        for offset in [8, 24, 40, 56] {
+            let mut corrupted = data.clone();
+            set_u64(&mut corrupted, offset, u64::MAX - 1);
+            assert!(!sections_within(
+                &parse_header(&corrupted).unwrap(),
+                file_length
+            ));
+        }
+    }
+
+    #[test]
+    fn ranges_map_to_blob_reads() {
+        let layout = ArchiveLayout {
+            segments: [("aa01", 10), ("bb02", 5), ("cc03", 20)]
+                .into_iter()
+                .map(|(hash, length)| BlobRef {
+                    hash: hash.to_string(),
+                    length,
+                })
+                .collect(),
+        };
+        let tiles_dir = Path::new("/tiles");
+        let reads = |start, end| -> Vec<(String, u64, u64)> {
+            range_segments(tiles_dir, &layout, start, end)
+                .into_iter()
+                .map(|(path, offset, length)| {
+                    (
+                        path.file_name().unwrap().to_string_lossy().to_string(),
+                        offset,
+                        length,
+                    )
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            range_segments(tiles_dir, &layout, 0, 0)[0].0,
+            Path::new("/tiles/aa/aa01")
+        );
+        assert_eq!(reads(0, 34).len(), 3);
+        assert_eq!(reads(3, 6), vec![("aa01".to_string(), 3, 4)]);
+        assert_eq!(
+            reads(8, 16),
+            vec![
+                ("aa01".to_string(), 8, 2),
+                ("bb02".to_string(), 0, 5),
+                ("cc03".to_string(), 0, 2),
+            ]
+        );
+        assert_eq!(reads(10, 14), vec![("bb02".to_string(), 0, 5)]);
+        assert_eq!(reads(34, 34), vec![("cc03".to_string(), 19, 1)]);
+    }
+}
@@ -1,66 +1,179 @@
+use crate::models::tor::{OnionState, TorStatus};
+use crate::utils::file::replace_private_file;
+use crate::utils::throttle::{Throttled, TokenBucket};
 use crate::AppState;
 use anyhow::Result;
-use arti_client::{TorClient, TorClientConfig};
+use arti_client::{
+    config::{CfgPath, TorClientConfigBuilder},
+    TorClient,
+};
 use axum::Router;
 use futures::StreamExt;
 use hyper::{body::Incoming, Request};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server;
 use safelog::{sensitive, DisplayRedacted as _};
-use std::sync::Arc;
-use tor_cell::relaycell::msg::Connected;
-use tor_hsservice::{config::OnionServiceConfigBuilder, StreamRequest};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hscrypto::pk::HsId;
+use tor_hsservice::{
+    config::{
+        restricted_discovery::DirectoryKeyProviderBuilder, OnionServiceConfig,
+        OnionServiceConfigBuilder, TokenBucketConfig,
+    },
+    status::State,
+    RendRequest, StreamRequest,
+};
 use tor_proto::client::stream::IncomingStreamRequest;
+use tor_rtcompat::PreferredRuntime;
 use tower::Service;
 use tracing::{debug, error, info, warn};
 
+pub const ONION_SERVICE_NICKNAME: &str = "localitysrv";
+/// The onion service of the admin API, with its own address and authorized clients
+pub const ADMIN_ONION_SERVICE_NICKNAME: &str = "localitysrv-admin";
+/// Consecutive failures after which the cached Tor client is dropped and bootstrapped anew
+const CLIENT_RESET_FAILURES: u32 = 3;
+/// Algorithm of the identity keys in Arti keystores
+const ONION_KEY_ALGORITHM: &str = "ed25519-expanded@spec.torproject.org";
+
+#[derive(Error, Debug)]
+pub enum TorError {
+    #[error("Onion address {actual} does not match the expected ONION_ADDRESS {expected}")]
+    AddressMismatch { expected: String, actual: String },
+    #[error("Onion service key error: {0}")]
+    KeyError(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Defenses of the introduction points, shared by both onion services.
+#[derive(Clone, Copy)]
+struct DosLimits {
+    pow: bool,
+    /// Introductions per second and burst at each introduction point
+    intro_rate_limit: Option<(u32, u32)>,
+}
+
+/// Limits applied to each rendezvous circuit, i.e. each connected client.
+#[derive(Clone, Copy)]
+struct CircuitLimits {
+    max_streams: usize,
+    /// Bytes per second shared by all streams of the circuit
+    bandwidth: Option<u64>,
+}
+
 pub struct TorServiceManager {
-    app: Router,
+    /// The router served on each virtual port of the public onion service
+    routers: Arc<HashMap<u16, Router>>,
+    /// The router served on each virtual port of the admin onion service, which is only
+    /// launched when there is one
+    admin_routers: Arc<HashMap<u16, Router>>,
     app_state: AppState,
     shutdown_signal: Arc<tokio::sync::Notify>,
+    /// Bootstrapped by the first successful attempt and reused by relaunches
+    client: tokio::sync::Mutex<Option<TorClient<PreferredRuntime>>>,
+    /// Set once the current attempt has made the service reachable
+    became_reachable: AtomicBool,
 }
 
 impl TorServiceManager {
     pub fn new(
-        app: Router,
+        routers: HashMap<u16, Router>,
+        admin_routers: HashMap<u16, Router>,
         app_state: AppState,
         shutdown_signal: Arc<tokio::sync::Notify>,
     ) -> Self {
         Self {
-            app,
+            routers: Arc::new(routers),
+            admin_routers: Arc::new(admin_routers),
             app_state,
             shutdown_signal,
+            client: tokio::sync::Mutex::new(None),
+            became_reachable: AtomicBool::new(false),
         }
     }
 
-    pub async fn run_with_retry(&self, onion_address_tx: tokio::sync::oneshot::Sender<String>) {
-        let mut retry_count = 0;
-        let max_retries = 5;
-        let base_delay = std::time::Duration::from_secs(5);
-        let mut onion_address_tx = Some(onion_address_tx);
+    pub async fn run_with_retry(&self) {
+        let (max_retries, base_delay, max_delay) = {
+            let config = self.app_state.config.lock().await;
+            (
+                config.tor_max_retries,
+                Duration::from_secs(config.tor_retry_base_delay_secs),
+                Duration::from_secs(config.tor_retry_max_delay_secs),
+            )
+        };
+        let mut retry_count: u32 = 0;
 
         loop {
-            let result = self.run_tor_service(onion_address_tx.take()).await;
+            self.update_status(|status| {
+                if status.attempt > 0 {
+                    status.restarts += 1;
+                }
+                status.attempt = retry_count.saturating_add(1);
+                status.max_attempts = max_retries.map(|max| max.saturating_add(1));
+                status.next_retry_at = None;
+            });
+
+            let result = self.run_tor_service().await;
+
+            // A service that went down after being reachable starts over with the
+            // shortest delay
+            if self.became_reachable.swap(false, Ordering::Relaxed) {
+                retry_count = 0;
+            }
 
             match result {
                 Ok(_) => {
                     info!("Tor hidden service stopped successfully");
+                    self.update_status(|status| status.set_state(OnionState::Stopped));
+                    break;
+                }
+                // Retrying cannot fix a wrong or missing identity
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<TorError>(),
+                        Some(TorError::AddressMismatch { .. } | TorError::KeyError(_))
+                    ) =>
+                {
+                    error!("{}. Not starting the Tor hidden service.", e);
+                    self.update_status(|status| status.failed(e.to_string()));
                     break;
                 }
-                Err(e) if retry_count >= max_retries => {
+                Err(e) if max_retries.is_some_and(|max| retry_count >= max) => {
                     error!(
                         "Max retries ({}) reached for Tor hidden service: {}. Giving up.",
-                        max_retries, e
+                        retry_count, e
                     );
+                    self.update_status(|status| status.failed(e.to_string()));
                     break;
                 }
                 Err(e) => {
-                    retry_count += 1;
-                    let delay = base_delay * retry_count;
+                    retry_count = retry_count.saturating_add(1);
+                    if retry_count.is_multiple_of(CLIENT_RESET_FAILURES)
+                        && self.client.lock().await.take().is_some()
+                    {
+                        warn!(
+                            "{} consecutive failures, bootstrapping a new Tor client",
+                            retry_count
+                        );
+                    }
+                    let delay = retry_delay(retry_count, base_delay, max_delay);
+                    let attempt = match max_retries {
+                        Some(max) => format!("{}/{}", retry_count, max),
+                        None => retry_count.to_string(),
+                    };
                     warn!(
-                        "Tor hidden service failed (attempt {}/{}): {}. Restarting in {:?}...",
-                        retry_count, max_retries, e, delay
+                        "Tor hidden service failed (attempt {}): {}. Restarting in {:?}...",
+                        attempt, e, delay
                     );
+                    self.update_status(|status| status.retrying(e.to_string(), delay));
 
                     tokio::time::sleep(delay).await;
                 }
@@ -68,21 +181,140 @@ impl TorServiceManager {
         }
     }
 
-    async fn run_tor_service(
+    /// Updates the onion service status shared with `/health`, `/onion` and the metrics.
+    fn update_status(&self, update: impl FnOnce(&mut TorStatus)) {
+        self.app_state.tor_status.update(update);
+    }
+
+    fn record_service_state(&self, service_state: State) {
+        if service_state.is_fully_reachable() {
+            self.became_reachable.store(true, Ordering::Relaxed);
+        }
+        self.update_status(|status| {
+            status.set_state(OnionState::from_service_state(service_state));
+            // Arti's name of the state, for display only
+            status.service_state = Some(format!("{:?}", service_state));
+        });
+    }
+
+    /// Returns the Tor client bootstrapped by an earlier attempt, or bootstraps a new one,
+    /// so that relaunching the onion service does not bootstrap again.
+    async fn bootstrapped_client(
         &self,
-        onion_address_tx: Option<tokio::sync::oneshot::Sender<String>>,
-    ) -> Result<()> {
-        info!("Starting Tor hidden service...");
+        state_dir: &Path,
+        cache_dir: &Path,
+    ) -> Result<TorClient<PreferredRuntime>> {
+        let mut cached_client = self.client.lock().await;
+        if let Some(client) = cached_client.as_ref() {
+            info!("Reusing the bootstrapped Tor client");
+            return Ok(client.clone());
+        }
 
-        let config = TorClientConfig::default();
+        self.update_status(|status| {
+            status.set_state(OnionState::Bootstrapping);
+            status.bootstrap_progress = 0;
+            status.bootstrap_blocked = None;
+        });
+
+        // The onion service identity key lives in the keystore of the state directory
+        let config = TorClientConfigBuilder::from_directories(state_dir, cache_dir).build()?;
 
         info!("Bootstrapping Tor client...");
-        let client = TorClient::create_bootstrapped(config).await?;
+        let client = TorClient::builder()
+            .config(config)
+            .create_unbootstrapped()?;
+
+        let progress_task = {
+            let tor_status = self.app_state.tor_status.clone();
+            let mut bootstrap_events = client.bootstrap_events();
+            tokio::spawn(async move {
+                while let Some(event) = bootstrap_events.next().await {
+                    tor_status.update(|status| {
+                        status.bootstrap_progress = (event.as_frac() * 100.0).round() as u8;
+                        status.bootstrap_blocked =
+                            event.blocked().map(|blockage| blockage.to_string());
+                    });
+                }
+            })
+        };
+        let bootstrapped = client.bootstrap().await;
+        progress_task.abort();
+        bootstrapped?;
+
         info!("Tor client bootstrapped successfully");
+        self.update_status(|status| {
+            status.bootstrap_progress = 100;
+            status.bootstrap_blocked = None;
+        });
+
+        *cached_client = Some(client.clone());
+        Ok(client)
+    }
+
+    async fn run_tor_service(&self) -> Result<()> {
+        info!("Starting Tor hidden service...");
+        self.update_status(|status| status.service_state = None);
+
+        // Everything the launch needs is read under a single lock of the config
+        let config = self.app_state.config.lock().await;
+        let state_dir = config.tor_state_dir();
+        let cache_dir = config.tor_cache_dir();
+        let expected_onion_address = config.expected_onion_address.clone();
+        let client_auth_dir = config
+            .restricted_discovery
+            .then(|| config.onion_client_auth_dir());
+        let admin_client_auth_dir = config.onion_admin_client_auth_dir();
+        let pow = config.onion_pow;
+        let intro_rate_limit = config
+            .onion_intro_rate_limit
+            .map(|rate| (rate, config.onion_intro_burst.unwrap_or(rate)));
+        let circuit_limits = CircuitLimits {
+            max_streams: match config.onion_max_streams_per_circuit {
+                0 => Semaphore::MAX_PERMITS,
+                max => max,
+            },
+            bandwidth: config.onion_circuit_bandwidth,
+        };
+        drop(config);
+
+        // Launching generates a new identity when the keystore has none, so the expected
+        // address is checked against the keystore first, before anything is published
+        if let Some(expected) = expected_onion_address {
+            match keystore_onion_address(&state_dir, ONION_SERVICE_NICKNAME)? {
+                Some(actual) if actual != expected => {
+                    return Err(TorError::AddressMismatch { expected, actual }.into());
+                }
+                Some(_) => {}
+                None => {
+                    return Err(TorError::KeyError(format!(
+                        "ONION_ADDRESS is {} but the keystore has no identity key, import it \
+                         with `onion-key import`",
+                        expected
+                    ))
+                    .into());
+                }
+            }
+        }
 
-        let svc_cfg = OnionServiceConfigBuilder::default()
-            .nickname("localitysrv".parse().unwrap())
-            .build()?;
+        let client = self.bootstrapped_client(&state_dir, &cache_dir).await?;
+        self.update_status(|status| status.set_state(OnionState::Launching));
+
+        let dos_limits = DosLimits {
+            pow,
+            intro_rate_limit,
+        };
+        let svc_cfg = onion_service_config(ONION_SERVICE_NICKNAME, client_auth_dir, dos_limits)?;
+        // The admin service is always restricted to its own clients, so the clients of the
+        // public service cannot discover it
+        let admin_svc_cfg = if self.admin_routers.is_empty() {
+            None
+        } else {
+            Some(onion_service_config(
+                ADMIN_ONION_SERVICE_NICKNAME,
+                Some(admin_client_auth_dir),
+                dos_limits,
+            )?)
+        };
 
         info!("Launching onion service...");
         let (service, request_stream) = client.launch_onion_service(svc_cfg)?;
@@ -95,19 +327,37 @@ impl TorServiceManager {
         {
             let mut config = self.app_state.config.lock().await;
             config.onion_address = Some(onion_address.clone());
+            // The identity, hence the URL, is the same for every launch
+            if let Some(onion_base_url) = config.onion_base_url() {
+                let _ = self.app_state.onion_base_url.set(onion_base_url);
+            }
         }
 
         info!("Tor hidden service launched at: {}", onion_address);
 
-        if let Some(tx) = onion_address_tx {
-            let _ = tx.send(onion_address.clone());
-        }
+        let (admin_service, mut admin_request_stream) = match admin_svc_cfg {
+            Some(admin_svc_cfg) => {
+                let (admin_service, admin_request_stream) =
+                    client.launch_onion_service(admin_svc_cfg)?;
+                if let Some(admin_onion_address) = admin_service.onion_address() {
+                    info!(
+                        "Admin onion service launched at: {}",
+                        admin_onion_address.display_unredacted()
+                    );
+                }
+                (Some(admin_service), admin_request_stream.boxed())
+            }
+            None => (None, futures::stream::pending().boxed()),
+        };
 
         info!("Waiting for Tor hidden service to be fully reachable...");
         let mut status_events = service.status_events();
 
         while let Some(status) = status_events.next().await {
-            if status.state().is_fully_reachable() {
+            let reachable = status.state().is_fully_reachable();
+            self.record_service_state(status.state());
+
+            if reachable {
                 info!(
                     "✓ Tor hidden service is now fully reachable at http://{}",
                     onion_address
@@ -119,8 +369,9 @@ impl TorServiceManager {
             status_events = service.status_events();
         }
 
-        let stream_requests = tor_hsservice::handle_rend_requests(request_stream);
-        tokio::pin!(stream_requests);
+        tokio::pin!(request_stream);
+
+        let mut reachable = true;
 
         loop {
             tokio::select! {
@@ -128,64 +379,450 @@ impl TorServiceManager {
                 _ = self.shutdown_signal.notified() => {
                     info!("Tor hidden service shutting down...");
                     drop(service);
+                    drop(admin_service);
                     return Ok(());
                 }
-                Some(stream_request) = stream_requests.next() => {
-                    let app_clone = self.app.clone();
+                Some(status) = status_events.next() => {
+                    let now_reachable = status.state().is_fully_reachable();
+                    if now_reachable != reachable {
+                        if now_reachable {
+                            info!("✓ Tor hidden service is fully reachable again");
+                        } else {
+                            warn!(
+                                "Tor hidden service is no longer fully reachable: {:?}",
+                                status.state()
+                            );
+                        }
+                        reachable = now_reachable;
+                    }
+                    self.record_service_state(status.state());
+                }
+                rend_request = request_stream.next() => {
+                    // The service stopped accepting requests, so relaunch it
+                    let Some(rend_request) = rend_request else {
+                        return Err(anyhow::anyhow!("Onion service request stream ended"));
+                    };
 
-                    tokio::spawn(async move {
-                        let request = stream_request.request().clone();
-                        if let Err(err) = handle_stream_request(stream_request, app_clone).await {
-                            warn!("Error serving connection {:?}: {}. Arti will handle recovery.", sensitive(request), err);
-                        };
-                    });
+                    tokio::spawn(handle_circuit(
+                        rend_request,
+                        self.routers.clone(),
+                        circuit_limits,
+                        self.app_state.clone(),
+                    ));
+                }
+                rend_request = admin_request_stream.next() => {
+                    let Some(rend_request) = rend_request else {
+                        return Err(anyhow::anyhow!("Admin onion service request stream ended"));
+                    };
+
+                    tokio::spawn(handle_circuit(
+                        rend_request,
+                        self.admin_routers.clone(),
+                        circuit_limits,
+                        self.app_state.clone(),
+                    ));
                 }
             }
         }
     }
 }
 
-async fn handle_stream_request(stream_request: StreamRequest, app: Router) -> Result<()> {
+/// Builds the configuration of the onion service `nickname`, discoverable only by the
+/// clients in `client_auth_dir` when given.
+fn onion_service_config(
+    nickname: &str,
+    client_auth_dir: Option<PathBuf>,
+    dos_limits: DosLimits,
+) -> Result<OnionServiceConfig> {
+    let mut svc_cfg = OnionServiceConfigBuilder::default();
+    svc_cfg.nickname(nickname.parse()?);
+
+    // Clients solve a proof-of-work puzzle whose effort rises with the load, so
+    // flooding the introduction points gets expensive
+    if dos_limits.pow {
+        info!("Onion service {} proof-of-work enabled", nickname);
+        svc_cfg.enable_pow(true);
+    }
+    if let Some((rate, burst)) = dos_limits.intro_rate_limit {
+        info!(
+            "Limiting introductions to {} to {}/s (burst {}) per introduction point",
+            nickname, rate, burst
+        );
+        svc_cfg.rate_limit_at_intro(Some(TokenBucketConfig::new(rate, burst)));
+    }
+
+    // Only clients whose keys are in the directory can discover the service. With
+    // `watch_configuration`, Arti watches the directory and republishes the descriptor
+    // when `.auth` files change (see "Live reloading" in tor-hsservice's
+    // `config::restricted_discovery`), so clients are added without a restart.
+    if let Some(client_auth_dir) = client_auth_dir {
+        std::fs::create_dir_all(&client_auth_dir)?;
+        restrict_permissions(&client_auth_dir, 0o700)?;
+
+        let client_count = list_authorized_clients(&client_auth_dir)?.len();
+        if client_count == 0 {
+            warn!(
+                "Restricted discovery is enabled for {} but no client is authorized",
+                nickname
+            );
+        }
+        info!(
+            "Restricted discovery enabled for {} with {} authorized clients",
+            nickname, client_count
+        );
+
+        let mut key_dir = DirectoryKeyProviderBuilder::default();
+        key_dir.path(CfgPath::new_literal(client_auth_dir));
+        svc_cfg
+            .restricted_discovery()
+            .enabled(true)
+            .watch_configuration(true)
+            .key_dirs()
+            .access()
+            .push(key_dir);
+    }
+
+    Ok(svc_cfg.build()?)
+}
+
+/// Serves the streams of one rendezvous circuit, at most `limits.max_streams` at a time
+/// and sharing `limits.bandwidth`.
+async fn handle_circuit(
+    rend_request: RendRequest,
+    routers: Arc<HashMap<u16, Router>>,
+    limits: CircuitLimits,
+    app_state: AppState,
+) {
+    let mut stream_requests = match rend_request.accept().await {
+        Ok(stream_requests) => stream_requests,
+        Err(e) => {
+            debug!("Failed to accept rendezvous request: {}", e);
+            return;
+        }
+    };
+
+    let streams = Arc::new(Semaphore::new(limits.max_streams));
+    let bandwidth = limits
+        .bandwidth
+        .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))));
+
+    while let Some(stream_request) = stream_requests.next().await {
+        let Ok(permit) = streams.clone().try_acquire_owned() else {
+            debug!("Rejecting stream request over the per-circuit limit");
+            app_state.tor_status.reject_over_stream_limit();
+            if let Err(e) = stream_request
+                .reject(End::new_with_reason(EndReason::RESOURCELIMIT))
+                .await
+            {
+                debug!("Failed to reject stream request: {}", e);
+            }
+            continue;
+        };
+
+        let routers = routers.clone();
+        let bandwidth = bandwidth.clone();
+        let app_state = app_state.clone();
+
+        tokio::spawn(async move {
+            let request = stream_request.request().clone();
+            if let Err(err) =
+                handle_stream_request(stream_request, routers, bandwidth, &app_state).await
+            {
+                warn!(
+                    "Error serving connection {:?}: {}. Arti will handle recovery.",
+                    sensitive(request),
+                    err
+                );
+            };
+            drop(permit);
+        });
+    }
+}
+
+/// Exponential backoff from `base_delay`, capped at `max_delay`. The upper half of the
+/// delay is random so that instances restarted together do not retry in lockstep.
+fn retry_delay(retry: u32, base_delay: Duration, max_delay: Duration) -> Duration {
+    let exponential = base_delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+    let half = exponential.min(max_delay) / 2;
+    half + half.mul_f64(rand::random::<f64>())
+}
+
+/// Path of the identity key of the onion service `nickname` in the Arti keystore of the
+/// state directory.
+pub fn onion_key_path(state_dir: &Path, nickname: &str) -> PathBuf {
+    state_dir
+        .join("keystore")
+        .join("hss")
+        .join(nickname)
+        .join("ks_hs_id.ed25519_expanded_private")
+}
+
+/// Copies the onion service identity key to `output_path`, e.g. to back it up or move the
+/// service to another machine.
+pub fn export_onion_key(
+    state_dir: &Path,
+    nickname: &str,
+    output_path: &Path,
+) -> Result<(), TorError> {
+    let key_path = onion_key_path(state_dir, nickname);
+
+    if !key_path.exists() {
+        return Err(TorError::KeyError(format!(
+            "No key at {}, start the server once to generate it",
+            key_path.display()
+        )));
+    }
+
+    replace_private_file(output_path, &std::fs::read(&key_path)?)?;
+    Ok(())
+}
+
+/// Onion address of the identity key in the keystore of the state directory, or `None`
+/// before the first start generates it.
+pub fn keystore_onion_address(
+    state_dir: &Path,
+    nickname: &str,
+) -> Result<Option<String>, TorError> {
+    let key_path = onion_key_path(state_dir, nickname);
+    if !key_path.exists() {
+        return Ok(None);
+    }
+
+    let content = std::fs::read_to_string(&key_path)?;
+    onion_address_of_key(&content, &key_path).map(Some)
+}
+
+/// Onion address of an OpenSSH-encoded Arti identity key, whose public part is the raw
+/// Ed25519 public key.
+fn onion_address_of_key(content: &str, key_path: &Path) -> Result<String, TorError> {
+    let invalid_key = || {
+        TorError::KeyError(format!(
+            "{} is not an Arti onion service identity key",
+            key_path.display()
+        ))
+    };
+
+    let key = ssh_key::PrivateKey::from_openssh(content).map_err(|_| invalid_key())?;
+    if key.algorithm().as_str() != ONION_KEY_ALGORITHM {
+        return Err(invalid_key());
+    }
+    let public_key: [u8; 32] = key
+        .public_key()
+        .key_data()
+        .other()
+        .and_then(|public_key| public_key.as_ref().try_into().ok())
+        .ok_or_else(invalid_key)?;
+
+    Ok(HsId::from(public_key).display_unredacted().to_string())
+}
+
+/// Installs an identity key exported by `export_onion_key`, or by Arti, in the keystore.
+pub fn import_onion_key(
+    state_dir: &Path,
+    nickname: &str,
+    key_path: &Path,
+    force: bool,
+) -> Result<(), TorError> {
+    let content = std::fs::read_to_string(key_path)?;
+    let onion_address = onion_address_of_key(&content, key_path)?;
+
+    let destination = onion_key_path(state_dir, nickname);
+    if destination.exists() && !force {
+        return Err(TorError::KeyError(format!(
+            "A key already exists at {}, use --force to replace it",
+            destination.display()
+        )));
+    }
+
+    // Arti refuses keystores that other users can access
+    let key_dir = destination.parent().unwrap_or(state_dir);
+    std::fs::create_dir_all(key_dir)?;
+    for dir in key_dir
+        .ancestors()
+        .take_while(|dir| dir.starts_with(state_dir))
+    {
+        restrict_permissions(dir, 0o700)?;
+    }
+
+    replace_private_file(&destination, content.as_bytes())?;
+    info!("Imported the identity key of {}", onion_address);
+    Ok(())
+}
+
+/// Authorizes a client to discover the service under restricted discovery. `key` is the
+/// client's public key, as `descriptor:x25519:<base32 key>`.
+pub fn add_authorized_client(
+    client_auth_dir: &Path,
+    nickname: &str,
+    key: &str,
+) -> Result<(), TorError> {
+    validate_client_nickname(nickname)?;
+
+    let key = key.trim();
+    let encoded_key = key.strip_prefix("descriptor:x25519:").unwrap_or("");
+    if encoded_key.len() != 52
+        || !encoded_key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c))
+    {
+        return Err(TorError::KeyError(
+            "Client keys must be formatted as descriptor:x25519:<base32 key>".to_string(),
+        ));
+    }
+
+    std::fs::create_dir_all(client_auth_dir)?;
+    restrict_permissions(client_auth_dir, 0o700)?;
+
+    let key_path = client_auth_dir.join(format!("{}.auth", nickname));
+    replace_private_file(&key_path, format!("{}\n", key).as_bytes())?;
+    Ok(())
+}
+
+/// Lists the nicknames and public keys of the authorized clients.
+pub fn list_authorized_clients(client_auth_dir: &Path) -> Result<Vec<(String, String)>, TorError> {
+    let mut clients = Vec::new();
+
+    if !client_auth_dir.exists() {
+        return Ok(clients);
+    }
+
+    for entry in std::fs::read_dir(client_auth_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "auth") {
+            if let Some(nickname) = path.file_stem().and_then(|stem| stem.to_str()) {
+                let key = std::fs::read_to_string(&path)?.trim().to_string();
+                clients.push((nickname.to_string(), key));
+            }
+        }
+    }
+    clients.sort();
+
+    Ok(clients)
+}
+
+pub fn revoke_authorized_client(client_auth_dir: &Path, nickname: &str) -> Result<(), TorError> {
+    validate_client_nickname(nickname)?;
+
+    let key_path = client_auth_dir.join(format!("{}.auth", nickname));
+    if !key_path.exists() {
+        return Err(TorError::KeyError(format!(
+            "No authorized client named {}",
+            nickname
+        )));
+    }
+
+    std::fs::remove_file(key_path)?;
+    Ok(())
+}
+
+fn validate_client_nickname(nickname: &str) -> Result<(), TorError> {
+    if nickname.is_empty()
+        || !nickname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(TorError::KeyError(format!(
+            "Invalid client nickname: {}",
+            nickname
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path, mode: u32) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path, _mode: u32) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+async fn handle_stream_request(
+    stream_request: StreamRequest,
+    routers: Arc<HashMap<u16, Router>>,
+    bandwidth: Option<Arc<Mutex<TokenBucket>>>,
+    app_state: &AppState,
+) -> Result<()> {
     debug!("Handling new stream request");
 
-    match stream_request.request() {
-        IncomingStreamRequest::Begin(begin) if begin.port() == 80 => {
-            debug!("Accepting stream request on port 80");
+    let route = match stream_request.request() {
+        IncomingStreamRequest::Begin(begin) => routers
+            .get(&begin.port())
+            .map(|app| (begin.port(), app.clone())),
+        _ => None,
+    };
 
-            match stream_request.accept(Connected::new_empty()).await {
-                Ok(onion_service_stream) => {
-                    debug!("Stream accepted successfully");
-                    let io = TokioIo::new(onion_service_stream);
+    let Some((port, app)) = route else {
+        debug!("Rejecting stream request on an unmapped port");
+        app_state.tor_status.reject_unmapped_port();
+        stream_request.shutdown_circuit()?;
+        return Ok(());
+    };
 
-                    let hyper_service =
-                        hyper::service::service_fn(move |request: Request<Incoming>| {
-                            app.clone().call(request)
-                        });
+    debug!("Accepting stream request on port {}", port);
 
-                    match server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection(io, hyper_service)
-                        .await
-                    {
-                        Ok(_) => {
-                            debug!("Connection served successfully");
-                            Ok(())
-                        }
-                        Err(e) => {
-                            warn!("Connection error: {}. Arti will handle recovery.", e);
-                            Err(anyhow::anyhow!(e))
-                        }
-                    }
+    match stream_request.accept(Connected::new_empty()).await {
+        Ok(onion_service_stream) => {
+            debug!("Stream accepted successfully");
+
+            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                app.clone().call(request)
+            });
+
+            let builder = server::conn::auto::Builder::new(TokioExecutor::new());
+            let served = match bandwidth {
+                Some(bucket) => {
+                    let io = TokioIo::new(Throttled::new(onion_service_stream, bucket));
+                    builder.serve_connection(io, hyper_service).await
+                }
+                None => {
+                    let io = TokioIo::new(onion_service_stream);
+                    builder.serve_connection(io, hyper_service).await
+                }
+            };
+
+            match served {
+                Ok(_) => {
+                    debug!("Connection served successfully");
+                    Ok(())
                 }
                 Err(e) => {
-                    warn!("Failed to accept stream: {}. Arti will handle recovery.", e);
+                    warn!("Connection error: {}. Arti will handle recovery.", e);
                     Err(anyhow::anyhow!(e))
                 }
             }
         }
-        _ => {
-            debug!("Rejecting stream request on non-port 80");
-            stream_request.shutdown_circuit()?;
-            Ok(())
+        Err(e) => {
+            warn!("Failed to accept stream: {}. Arti will handle recovery.", e);
+            Err(anyhow::anyhow!(e))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let base = Duration::from_secs(5);
+        let max = Duration::from_secs(300);
+
+        for (retry, full) in [(1, 5), (2, 10), (3, 20), (4, 40), (7, 300), (40, 300)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..20 {
+                let delay = retry_delay(retry, base, max);
+                assert!(delay >= full / 2 && delay <= full, "{}: {:?}", retry, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn retry_delay_does_not_overflow() {
+        let delay = retry_delay(u32::MAX, Duration::from_secs(u64::MAX / 2), Duration::MAX);
+        assert!(delay >= Duration::MAX / 2);
+    }
+}
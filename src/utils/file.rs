@@ -57,9 +57,66 @@ pub async fn download_file_with_progress(url: &str, destination: &Path) -> Resul
     Ok(())
 }
 
+/// Available space in bytes on the filesystem holding `path`, as reported by `df`.
+pub async fn available_disk_space(df_cmd: &str, path: &Path) -> Result<u64, FileError> {
+    let output = crate::utils::cmd::run_command(df_cmd, &["-Pk", &path.to_string_lossy()], None)
+        .await
+        .map_err(|e| FileError::FileOperationFailed(e.to_string()))?;
+
+    // POSIX output: a header line, then "Filesystem 1024-blocks Used Available Capacity Mounted"
+    output
+        .stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .map(|available| available * 1024)
+        .ok_or_else(|| {
+            FileError::FileOperationFailed(format!("Unexpected df output: {}", output.stdout))
+        })
+}
+
 pub fn ensure_dir_exists(path: &Path) -> Result<(), FileError> {
     if !path.exists() {
         fs::create_dir_all(path).map_err(|e| FileError::IoError(e.to_string()))?;
     }
     Ok(())
 }
+
+/// Creates `path` with `bytes`, readable and writable by the owner only. The mode is set
+/// when the file is created, so the contents are never readable by others, and an
+/// existing file is never overwritten.
+pub fn write_private_file(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Replaces `path` with `bytes`, readable and writable by the owner only. The contents are
+/// written to a temporary file next to `path` and renamed over it, so readers see either
+/// the old or the new file.
+pub fn replace_private_file(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no file name"))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    if let Err(e) = write_private_file(&tmp_path, bytes) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, path)
+}
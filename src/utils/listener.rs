@@ -0,0 +1,229 @@
+use crate::config::BindAddress;
+use crate::utils::tls::TlsState;
+use axum::Router;
+use hyper::{body::Incoming, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tower::Service;
+use tracing::{debug, error, info, warn};
+
+/// Time a client gets to complete the TLS handshake before the connection is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, e.g. when out of file descriptors, as axum does
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Socket file to remove on shutdown; `None` for sockets owned by systemd
+        path: Option<PathBuf>,
+    },
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "http://{}", address),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix {
+                path: Some(path), ..
+            } => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Listener::Unix { path: None, .. } => write!(f, "unix:<systemd>"),
+        }
+    }
+}
+
+/// Binds one configured address. Systemd activation may yield several sockets.
+pub async fn bind(address: &BindAddress, unix_socket_mode: u32) -> io::Result<Vec<Listener>> {
+    match address {
+        BindAddress::Tcp(address) => Ok(vec![Listener::Tcp(
+            tokio::net::TcpListener::bind(address).await?,
+        )]),
+        BindAddress::Unix(path) => bind_unix(path, unix_socket_mode).map(|l| vec![l]),
+        BindAddress::Systemd => take_systemd_listeners(),
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> io::Result<Listener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket left behind by an unclean shutdown would make bind fail
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+    Ok(Listener::Unix {
+        listener,
+        path: Some(path.to_path_buf()),
+    })
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path, _mode: u32) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+fn take_systemd_listeners() -> io::Result<Vec<Listener>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let mut listeners = Vec::new();
+
+    for idx in 0..fds.len() {
+        // Taking a socket of the wrong type fails and leaves it in place
+        if let Ok(Some(listener)) = fds.take_tcp_listener(idx) {
+            listener.set_nonblocking(true)?;
+            listeners.push(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?));
+            continue;
+        }
+
+        #[cfg(unix)]
+        if let Some(listener) = fds.take_unix_listener(idx)? {
+            listener.set_nonblocking(true)?;
+            listeners.push(Listener::Unix {
+                listener: tokio::net::UnixListener::from_std(listener)?,
+                path: None,
+            });
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no sockets were passed by systemd (LISTEN_FDS)",
+        ));
+    }
+
+    Ok(listeners)
+}
+
+/// Serves `app` on a bound listener until `shutdown_signal` fires. TCP
+/// listeners speak TLS when `tls` is given.
+pub async fn serve(
+    app: Router,
+    name: String,
+    listener: Listener,
+    tls: Option<TlsState>,
+    shutdown_signal: Arc<Notify>,
+) {
+    let mut description = listener.to_string();
+    if let (Listener::Tcp(_), Some(_)) = (&listener, &tls) {
+        description = description.replacen("http://", "https://", 1);
+    }
+    info!("{} server listening on {}", name, description);
+
+    let shutdown = {
+        let name = name.clone();
+        let description = description.clone();
+        async move {
+            shutdown_signal.notified().await;
+            info!("{} server on {} shutting down...", name, description);
+        }
+    };
+
+    let result = match (listener, tls) {
+        (Listener::Tcp(listener), Some(tls)) => serve_tls(app, listener, tls, shutdown).await,
+        (Listener::Tcp(listener), None) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        #[cfg(unix)]
+        (Listener::Unix { listener, path }, _) => {
+            let result = axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await;
+            if let Some(path) = path {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Failed to remove socket {}: {}", path.display(), e);
+                }
+            }
+            result
+        }
+    };
+
+    match result {
+        Ok(_) => info!("{} server on {} stopped successfully", name, description),
+        Err(e) => error!("{} server error on {}: {}", name, description, e),
+    }
+}
+
+async fn serve_tls(
+    app: Router,
+    listener: tokio::net::TcpListener,
+    tls: TlsState,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> io::Result<()> {
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept TLS connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+        };
+
+        let acceptor = tls.acceptor();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", peer);
+                        return;
+                    }
+                };
+
+            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                app.clone().call(request)
+            });
+
+            if let Err(e) = server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), hyper_service)
+                .await
+            {
+                debug!("TLS connection with {} ended with an error: {}", peer, e);
+            }
+        });
+    }
+}
@@ -1,2 +1,5 @@
 pub mod cmd;
 pub mod file;
+pub mod listener;
+pub mod throttle;
+pub mod tls;
@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Writes wait for at most this many bytes worth of tokens, so that a throttled
+/// stream sends reasonably sized chunks instead of single bytes.
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// A token bucket of bytes, refilled at `rate` bytes per second and holding at most
+/// one second worth of tokens.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            tokens: rate.max(1) as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated_at = now;
+    }
+
+    /// How many of `wanted` bytes may be sent now, or how long to wait for them.
+    fn available(&mut self, wanted: usize) -> Result<usize, Duration> {
+        self.refill();
+
+        let wanted = wanted.min(MAX_CHUNK).min(self.rate as usize).max(1);
+        if self.tokens >= wanted as f64 {
+            Ok(wanted)
+        } else {
+            Err(Duration::from_secs_f64(
+                (wanted as f64 - self.tokens) / self.rate,
+            ))
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Limits the bytes written to `inner` by a token bucket that may be shared with
+/// other streams, e.g. all streams of one onion service circuit. Reads are not limited.
+pub struct Throttled<S> {
+    inner: S,
+    bucket: Arc<Mutex<TokenBucket>>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bucket: Arc<Mutex<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            bucket,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+
+            let available = self.bucket.lock().unwrap().available(buf.len());
+            match available {
+                Ok(bytes) => {
+                    let this = &mut *self;
+                    let written = Pin::new(&mut this.inner).poll_write(cx, &buf[..bytes]);
+                    if let Poll::Ready(Ok(written)) = written {
+                        this.bucket.lock().unwrap().consume(written);
+                    }
+                    return written;
+                }
+                Err(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_starts_full_and_caps_chunks() {
+        let mut bucket = TokenBucket::new(100 * 1024);
+        assert_eq!(bucket.available(1000), Ok(1000));
+        assert_eq!(bucket.available(usize::MAX), Ok(MAX_CHUNK));
+
+        let mut slow = TokenBucket::new(100);
+        assert_eq!(slow.available(1000), Ok(100));
+    }
+
+    #[test]
+    fn token_bucket_waits_for_missing_tokens() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.consume(1000);
+
+        let wait = bucket.available(100).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_one_second() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.consume(1000);
+
+        bucket.updated_at -= Duration::from_millis(500);
+        assert_eq!(bucket.available(400), Ok(400));
+        assert!(bucket.available(600).is_err());
+
+        bucket.updated_at -= Duration::from_secs(10);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 1000.0);
+    }
+}
@@ -0,0 +1,112 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read certificate {0}: {1}")]
+    Certificate(PathBuf, String),
+    #[error("Failed to read private key {0}: {1}")]
+    PrivateKey(PathBuf, String),
+    #[error("Invalid TLS configuration: {0}")]
+    Config(#[from] rustls::Error),
+}
+
+/// The TLS configuration of the clearnet listener, swapped in place whenever
+/// the certificate or key changes on disk.
+#[derive(Clone)]
+pub struct TlsState {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl TlsState {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        let config = load_server_config(cert_path, key_path)?;
+
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            acceptor: Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(config)))),
+        })
+    }
+
+    /// The acceptor for a new connection. Connections already established
+    /// keep the configuration they were accepted with.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Polls the certificate and key for changes until `shutdown_signal`
+    /// fires. A pair that fails to load keeps the previous configuration.
+    pub async fn watch(self, shutdown_signal: Arc<Notify>) {
+        let shutdown = shutdown_signal.notified();
+        tokio::pin!(shutdown);
+
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = interval.tick() => {}
+            }
+
+            let modified = self.modified();
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match load_server_config(&self.cert_path, &self.key_path) {
+                Ok(config) => {
+                    *self.acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(config));
+                    info!("Reloaded TLS certificate from {}", self.cert_path.display());
+                }
+                Err(e) => warn!("Keeping the previous TLS certificate: {}", e),
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Certificate(cert_path.to_path_buf(), e.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::Certificate(
+            cert_path.to_path_buf(),
+            "no certificate found".to_string(),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| TlsError::PrivateKey(key_path.to_path_buf(), e.to_string()))?;
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+    // HTTP/2 is negotiated through ALPN, falling back to HTTP/1.1
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}